@@ -0,0 +1,270 @@
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config;
+use crate::error::HelmError;
+use crate::utils;
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub sha256: String,
+    pub size: u64,
+    pub file_name: String,
+}
+
+impl Cache {
+    pub fn new(root: &str) -> Cache {
+        Cache {
+            root: PathBuf::from(shellexpand::tilde(root).to_string()),
+        }
+    }
+
+    pub fn from_config() -> Option<Cache> {
+        config::get_config().cache.dir.as_deref().map(Cache::new)
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<CacheEntry> {
+        let data = fs::read_to_string(self.get_index_path(key)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&data).ok()?;
+        match self.get_blob_path(&entry.sha256).is_file() {
+            true => Some(entry),
+            false => None,
+        }
+    }
+
+    pub fn fetch(&self, key: &str, output_file: &Path) -> Result<Option<CacheEntry>, HelmError> {
+        let entry = match self.lookup(key) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let blob_path = self.get_blob_path(&entry.sha256);
+        if let Err(e) = verify_file(&blob_path, &entry.sha256) {
+            warn!("cache entry {} is corrupted, remove it: {}", key, e);
+            fs::remove_file(&blob_path)?;
+            fs::remove_file(self.get_index_path(key))?;
+            return Ok(None);
+        }
+        let temp_file = utils::get_temp_path(output_file);
+        link_or_copy(&blob_path, &temp_file)?;
+        fs::rename(&temp_file, output_file)?;
+        info!(
+            "{} fetched from cache {}",
+            output_file.display(),
+            &entry.sha256
+        );
+        Ok(Some(entry))
+    }
+
+    pub fn store(&self, key: &str, file: &Path) -> Result<CacheEntry, HelmError> {
+        let sha256 = get_file_sha256(file)?;
+        let blob_path = self.get_blob_path(&sha256);
+        if !blob_path.is_file() {
+            fs::create_dir_all(blob_path.parent().unwrap())?;
+            let temp_file = utils::get_temp_path(&blob_path);
+            link_or_copy(file, &temp_file)?;
+            fs::rename(&temp_file, &blob_path)?;
+        }
+        let entry = CacheEntry {
+            sha256,
+            size: fs::metadata(file)?.len(),
+            file_name: file
+                .file_name()
+                .map_or(String::new(), |x| x.to_string_lossy().to_string()),
+        };
+        let index_path = self.get_index_path(key);
+        fs::create_dir_all(index_path.parent().unwrap())?;
+        utils::write_file_atomic(
+            &index_path,
+            serde_json::to_string_pretty(&entry)?.as_bytes(),
+        )?;
+        debug!("{} stored to cache {}", file.display(), &entry.sha256);
+        Ok(entry)
+    }
+
+    fn get_blob_path(&self, sha256: &str) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join("sha256")
+            .join(&sha256[..2])
+            .join(sha256)
+    }
+
+    fn get_index_path(&self, key: &str) -> PathBuf {
+        self.root.join("index").join(format!("{}.json", key))
+    }
+}
+
+// the counterparts of lookup, fetch and store for the async helm, on tokio::fs
+#[cfg(feature = "async")]
+impl Cache {
+    pub async fn lookup_async(&self, key: &str) -> Option<CacheEntry> {
+        let data = tokio::fs::read_to_string(self.get_index_path(key))
+            .await
+            .ok()?;
+        let entry: CacheEntry = serde_json::from_str(&data).ok()?;
+        match tokio::fs::metadata(self.get_blob_path(&entry.sha256)).await {
+            Ok(v) if v.is_file() => Some(entry),
+            _ => None,
+        }
+    }
+
+    pub async fn fetch_async(
+        &self,
+        key: &str,
+        output_file: &Path,
+    ) -> Result<Option<CacheEntry>, HelmError> {
+        let entry = match self.lookup_async(key).await {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let blob_path = self.get_blob_path(&entry.sha256);
+        if let Err(e) = verify_file_async(&blob_path, &entry.sha256).await {
+            warn!("cache entry {} is corrupted, remove it: {}", key, e);
+            tokio::fs::remove_file(&blob_path).await?;
+            tokio::fs::remove_file(self.get_index_path(key)).await?;
+            return Ok(None);
+        }
+        let temp_file = utils::get_temp_path(output_file);
+        link_or_copy_async(&blob_path, &temp_file).await?;
+        tokio::fs::rename(&temp_file, output_file).await?;
+        info!(
+            "{} fetched from cache {}",
+            output_file.display(),
+            &entry.sha256
+        );
+        Ok(Some(entry))
+    }
+
+    pub async fn store_async(&self, key: &str, file: &Path) -> Result<CacheEntry, HelmError> {
+        let sha256 = get_file_sha256_async(file).await?;
+        let blob_path = self.get_blob_path(&sha256);
+        if !tokio::fs::metadata(&blob_path)
+            .await
+            .is_ok_and(|x| x.is_file())
+        {
+            tokio::fs::create_dir_all(blob_path.parent().unwrap()).await?;
+            let temp_file = utils::get_temp_path(&blob_path);
+            link_or_copy_async(file, &temp_file).await?;
+            tokio::fs::rename(&temp_file, &blob_path).await?;
+        }
+        let entry = CacheEntry {
+            sha256,
+            size: tokio::fs::metadata(file).await?.len(),
+            file_name: file
+                .file_name()
+                .map_or(String::new(), |x| x.to_string_lossy().to_string()),
+        };
+        let index_path = self.get_index_path(key);
+        tokio::fs::create_dir_all(index_path.parent().unwrap()).await?;
+        let temp_path = utils::get_temp_path(&index_path);
+        let data = serde_json::to_string_pretty(&entry)?;
+        let result = async {
+            let mut f_o = tokio::fs::File::create(&temp_path).await?;
+            f_o.write_all(data.as_bytes()).await?;
+            f_o.sync_all().await?;
+            tokio::fs::rename(&temp_path, &index_path).await
+        };
+        if let Err(e) = result.await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        debug!("{} stored to cache {}", file.display(), &entry.sha256);
+        Ok(entry)
+    }
+}
+
+pub fn get_extension_key(
+    publisher: &str,
+    package: &str,
+    version: &str,
+    platform: Option<&str>,
+) -> String {
+    let version = match platform {
+        Some(platform) => format!("{}={}", version, platform),
+        None => version.to_string(),
+    };
+    format!("extensions/{}.{}/{}", publisher, package, version).to_lowercase()
+}
+
+pub fn get_server_key(quality: &str, prefix: &str, arch: &str, commit: &str) -> String {
+    format!("server/{}/{}-{}/{}", quality, prefix, arch, commit).to_lowercase()
+}
+
+pub fn get_file_sha256(file: &Path) -> Result<String, HelmError> {
+    let mut hasher = Sha256::new();
+    let mut f_i = File::open(file)?;
+    io::copy(&mut f_i, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn verify_file(file: &Path, sha256: &str) -> Result<(), HelmError> {
+    let actual = get_file_sha256(file)?;
+    if actual != sha256 {
+        return Err(HelmError::ChecksumMismatch {
+            path: file.to_path_buf(),
+            expected: sha256.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    match fs::hard_link(src, dst) {
+        Err(e) => {
+            debug!("hard link {} failed, copy it: {}", dst.display(), e);
+            fs::copy(src, dst).map(|_| ())
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+#[cfg(feature = "async")]
+pub async fn get_file_sha256_async(file: &Path) -> Result<String, HelmError> {
+    let mut hasher = Sha256::new();
+    let mut f_i = tokio::fs::File::open(file).await?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let size = f_i.read(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..size]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(feature = "async")]
+async fn verify_file_async(file: &Path, sha256: &str) -> Result<(), HelmError> {
+    let actual = get_file_sha256_async(file).await?;
+    if actual != sha256 {
+        return Err(HelmError::ChecksumMismatch {
+            path: file.to_path_buf(),
+            expected: sha256.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+async fn link_or_copy_async(src: &Path, dst: &Path) -> io::Result<()> {
+    match tokio::fs::hard_link(src, dst).await {
+        Err(e) => {
+            debug!("hard link {} failed, copy it: {}", dst.display(), e);
+            tokio::fs::copy(src, dst).await.map(|_| ())
+        }
+        Ok(_) => Ok(()),
+    }
+}
@@ -0,0 +1,160 @@
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::value as json_value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{BoxError, HelmError};
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+static CONFIG_NAME: &str = "vscode-extension-helm";
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub extension: ExtensionConfig,
+    pub server: ServerConfig,
+    pub registry: RegistryConfig,
+    pub proxy: ProxyConfig,
+    pub tls: TlsConfig,
+    pub retry: RetryConfig,
+    pub cache: CacheConfig,
+    pub extension_sets: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtensionConfig {
+    pub download_dir: Option<String>,
+    pub cached: Option<bool>,
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub platform: Option<String>,
+    pub arch: Option<String>,
+    pub output_dir: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    pub query_url: Option<String>,
+    pub download_url: Option<String>,
+    pub update_url: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub url: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca_bundle: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub retries: Option<u32>,
+    pub initial_delay: Option<f64>,
+    pub max_delay: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub dir: Option<String>,
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+pub fn set_config(config: Config) {
+    if CONFIG.set(config).is_err() {
+        log::warn!("config is already initialized");
+    }
+}
+
+pub fn get_config_files(config_file: Option<&str>) -> Vec<PathBuf> {
+    let user_dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(v) if !v.is_empty() => PathBuf::from(v),
+        _ => PathBuf::from(shellexpand::tilde("~/.config").to_string()),
+    };
+    let user_dir = user_dir.join(CONFIG_NAME);
+    let mut files: Vec<PathBuf> = ["config.toml", "config.json"]
+        .iter()
+        .map(|x| user_dir.join(x))
+        .filter(|x| x.exists())
+        .collect();
+    match config_file {
+        Some(v) => files.push(PathBuf::from(shellexpand::tilde(v).to_string())),
+        None => {
+            let project_files = [
+                format!("{}.toml", CONFIG_NAME),
+                format!("{}.json", CONFIG_NAME),
+            ];
+            files.extend(
+                project_files
+                    .iter()
+                    .map(PathBuf::from)
+                    .filter(|x| x.exists()),
+            );
+        }
+    }
+    files
+}
+
+pub fn load_config(files: &[PathBuf]) -> Result<Config, HelmError> {
+    let mut data = json_value::Value::Object(json_value::Map::new());
+    for file in files {
+        merge_value(&mut data, read_config_file(file)?);
+    }
+    let config: Config = serde_json::from_value(data).map_err(|e| HelmError::Config {
+        message: String::from("load the merged config failed"),
+        source: Some(Box::new(e)),
+    })?;
+    Ok(config)
+}
+
+pub fn read_config_file(file: &Path) -> Result<json_value::Value, HelmError> {
+    let file_text = file.to_string_lossy();
+    let content = fs::read_to_string(file)
+        .map_err(|e| HelmError::from(e).context(format!("read config {} failed", file_text)))?;
+    let parse_error = |e: BoxError| HelmError::Config {
+        message: format!("parse {} failed", file_text),
+        source: Some(e),
+    };
+    let data = match file.extension().and_then(|x| x.to_str()) {
+        Some("json") => serde_json::from_str(&content).map_err(|e| parse_error(Box::new(e)))?,
+        _ => toml::from_str(&content).map_err(|e| parse_error(Box::new(e)))?,
+    };
+    Ok(data)
+}
+
+fn merge_value(base: &mut json_value::Value, other: json_value::Value) {
+    match (base, other) {
+        (json_value::Value::Object(base), json_value::Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(v) => merge_value(v, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
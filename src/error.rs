@@ -0,0 +1,116 @@
+use std::error::Error as StdError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+// the causes from the zip, xml, toml and tls libraries
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum HelmError {
+    #[error("extension {0} not found in marketplace")]
+    ExtensionNotFound(String),
+    #[error("no version of extension {0} matches the requested version and platform")]
+    VersionNotFound(String),
+    #[error("extension {0} is not resolved")]
+    NotResolved(String),
+    #[error("invalid extension {entry}: {message}")]
+    InvalidExtension { entry: String, message: String },
+    #[error("invalid platform {platform}, choices in ({choices})")]
+    InvalidPlatform { platform: String, choices: String },
+    #[error("invalid {name} {value}")]
+    InvalidOption { name: String, value: String },
+    #[error("no vscode server release found for {quality} {platform}-{arch}")]
+    ReleaseNotFound {
+        quality: String,
+        platform: String,
+        arch: String,
+    },
+    #[error("request {url} failed: HTTP status {status}")]
+    HttpStatus { url: String, status: u16 },
+    #[error("invalid response from {url}: {message}")]
+    InvalidResponse { url: String, message: String },
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("checksum mismatch for {}: expected {expected}, actual {actual}", path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    #[error("invalid vsix {}: {message}", path.display())]
+    InvalidVsix {
+        path: PathBuf,
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+    #[error("extract {archive} failed: {message}")]
+    Extract {
+        archive: String,
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+    #[error("{0} is not supported by the registry")]
+    Unsupported(String),
+    #[error("invalid config: {message}")]
+    Config {
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+    #[error("{message}")]
+    Context {
+        message: String,
+        #[source]
+        source: Box<HelmError>,
+    },
+}
+
+impl HelmError {
+    pub fn context(self, message: impl Into<String>) -> HelmError {
+        HelmError::Context {
+            message: message.into(),
+            source: Box::new(self),
+        }
+    }
+
+    pub fn root(&self) -> &HelmError {
+        match self {
+            HelmError::Context { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+}
+
+// the message of the error and its sources, joined for showing to the user
+pub fn report(error: &dyn StdError) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_message() {
+        let error = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        let error = HelmError::from(error)
+            .context("read a failed")
+            .context("load b failed");
+        assert_eq!(error.to_string(), "load b failed");
+        assert_eq!(report(&error), "load b failed: read a failed: no such file");
+        assert!(matches!(error.root(), HelmError::Io(_)));
+    }
+}
@@ -1,106 +1,362 @@
-use anyhow::{Context, Result};
 use flate2::read::GzDecoder;
-use log::{debug, info};
-use reqwest::{self};
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use semver::{Version, VersionReq};
 use serde_json::from_str as json_from_str;
 use serde_json::json;
 use serde_json::value as json_value;
-use shellexpand;
-use std::error::Error;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, BufReader};
 use std::path::MAIN_SEPARATOR;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+#[cfg(feature = "async")]
+use tokio::sync::Semaphore;
+#[cfg(feature = "async")]
+use tokio::task::JoinSet;
 
+use crate::cache;
+use crate::error::{self, HelmError};
+use crate::gallery::{GalleryExtension, GalleryResponse, GalleryResult, GalleryVersion};
+use crate::helm::{Helm, HelmBuilder, DOWNLOAD_URL};
+#[cfg(feature = "async")]
+use crate::nonblocking::AsyncHelm;
+use crate::progress::Counter;
 use crate::utils;
+use crate::vsix;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Extension {
     publisher: String,
     package: String,
     version: Option<String>,
     platform: Option<String>,
+    download_url: Option<String>,
 }
-static QUERY_URL: &str = "https://marketplace.visualstudio.com/_apis/public/gallery/extensionQuery";
-static DOWNLOAD_URL: &str = "https://marketplace.visualstudio.com/_apis/public/gallery/publishers/{}/vsextensions/{}/{}/vspackage";
+
+#[derive(Debug)]
+pub struct Outdated {
+    pub id: String,
+    pub current: Option<String>,
+    pub current_engine: Option<String>,
+    pub latest: Option<String>,
+    pub latest_engine: Option<String>,
+}
+// an entry of the extension list that could not be parsed, the line is unknown for json files
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub source: String,
+    pub line: Option<usize>,
+    pub entry: String,
+    pub message: String,
+}
+
+pub static SORT_OPTIONS: [(&str, usize); 4] = [
+    ("relevance", 0),
+    ("installs", 4),
+    ("rating", 12),
+    ("updated", 1),
+];
 
 impl Extension {
     pub fn get_extension_name(&self) -> String {
         get_extension_name(
             self.publisher.as_ref(),
             self.package.as_ref(),
-            self.version.as_ref().map(|x| x.as_str()),
-            self.platform.as_ref().map(|x| x.as_str()),
+            self.version.as_deref(),
+            self.platform.as_deref(),
         )
     }
 
-    pub fn check_platform(&self) -> Result<(), Box<dyn Error>> {
-        let valid_platforms = vec![
-            ("win32-x64", "Windows x64"),
-            ("win32-ia32", "Windows ia32"),
-            ("win32-arm64", "Windows ARM"),
-            ("linux-x64", "Linux x64"),
-            ("linux-arm64", "Linux ARM64"),
-            ("linux-armhf", "Linux ARM32"),
-            ("darwin-x64", "macOS Intel"),
-            ("darwin-arm64", "macOS Apple Silicon"),
-            ("alpine-x64", "Alpine Linux 64 bit"),
-            ("web", "Web"),
-            ("alpine-arm64", "Alpine Linux ARM64"),
-        ];
+    pub fn get_publisher(&self) -> &str {
+        &self.publisher
+    }
+
+    pub fn get_package(&self) -> &str {
+        &self.package
+    }
+
+    pub fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn get_platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    // e.g. "^2024.4" or "<2024.8", resolved to the newest matched version
+    pub fn get_version_req(&self) -> Option<VersionReq> {
+        let version = self.version.as_deref()?;
+        match is_version_range(version) {
+            true => VersionReq::parse(version).ok(),
+            false => None,
+        }
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.get_resolved_version().is_some()
+    }
+
+    fn get_resolved_version(&self) -> Option<&str> {
+        self.version.as_deref().filter(|x| !is_version_range(x))
+    }
+
+    pub fn check_platform(&self) -> Result<(), HelmError> {
         let passed = match &self.platform {
             None => true,
-            Some(platform) => valid_platforms.iter().any(|x| platform == x.0),
+            Some(platform) => is_platform(platform),
         };
         if passed {
             Ok(())
         } else {
-            let choices = valid_platforms
+            let choices = PLATFORMS
                 .iter()
                 .map(|x| x.0)
                 .collect::<Vec<&str>>()
                 .join(", ");
-            let message = format!(
-                "invalid platform {}, choices in ({})",
-                self.platform.as_ref().unwrap(),
-                choices
-            );
-            Err(message.into())
+            Err(HelmError::InvalidPlatform {
+                platform: self.platform.clone().unwrap(),
+                choices,
+            })
         }
     }
 
-    pub fn download(
+    pub fn new(
+        publisher: &str,
+        package: &str,
+        version: Option<&str>,
+        platform: Option<&str>,
+    ) -> Extension {
+        Extension {
+            publisher: publisher.to_string(),
+            package: package.to_string(),
+            version: version.map(str::to_string),
+            platform: platform.map(str::to_string),
+            download_url: None,
+        }
+    }
+
+    pub fn get_extension_id(&self) -> String {
+        get_extension_name(&self.publisher, &self.package, None, None)
+    }
+
+    // the identifier rules of the marketplace, and the versions are semver
+    pub fn validate(&self) -> Result<(), HelmError> {
+        let invalid = |message: String| HelmError::InvalidExtension {
+            entry: self.get_extension_name(),
+            message,
+        };
+        for (name, value) in [("publisher", &self.publisher), ("name", &self.package)] {
+            if !is_valid_identifier(value) {
+                return Err(invalid(format!("invalid {} '{}'", name, value)));
+            }
+        }
+        if let Some(version) = &self.version {
+            // a partial version like "1.0" would be read as "^1.0", ask for the range instead
+            if !is_version_range(version)
+                && Version::parse(version).is_err()
+                && VersionReq::parse(version).is_ok()
+            {
+                return Err(invalid(format!(
+                    "invalid version '{}': expect <major>.<minor>.<patch>, or a range like ^{}",
+                    version, version
+                )));
+            }
+            match is_version_range(version) {
+                true => VersionReq::parse(version).map(|_| ()),
+                false => Version::parse(version).map(|_| ()),
+            }
+            .map_err(|e| invalid(format!("invalid version '{}': {}", version, e)))?;
+        }
+        self.check_platform()
+    }
+
+    pub fn resolve(&self) -> Result<Extension, HelmError> {
+        Helm::from_config()?.resolve(self)
+    }
+
+    pub fn resolve_from(
+        &self,
+        ext_data: Option<&GalleryExtension>,
+    ) -> Result<Extension, HelmError> {
+        self.check_platform()?;
+        match (self.is_resolved(), ext_data) {
+            (true, _) => Ok(self.clone()),
+            (false, Some(ext_data)) => {
+                let ver_data = self.select_version(ext_data)?;
+                Ok(Extension {
+                    version: Some(ver_data.version.clone()),
+                    platform: ver_data.target_platform.clone(),
+                    download_url: ver_data.get_download_url(),
+                    ..self.clone()
+                })
+            }
+            (false, None) => Err(HelmError::ExtensionNotFound(self.get_extension_id())),
+        }
+    }
+
+    pub fn download(&self, download_dir: &str, cached: Option<bool>) -> Result<bool, HelmError> {
+        Helm::from_config()?.download_extension(self, download_dir, cached)
+    }
+
+    pub fn download_resolved(
         &self,
-        download_dir: &String,
+        download_dir: &str,
         cached: Option<bool>,
-    ) -> Result<bool, Box<dyn Error>> {
-        let cached = match cached {
-            Some(val) => val,
-            None => true,
+    ) -> Result<bool, HelmError> {
+        Helm::from_config()?.download_resolved(self, download_dir, cached)
+    }
+
+    pub fn query_version(&self) -> Result<GalleryVersion, HelmError> {
+        let ext_data = query_extension(&self.publisher, &self.package, None)?;
+        self.select_version(&ext_data).cloned()
+    }
+
+    pub fn select_version<'a>(
+        &self,
+        ext_data: &'a GalleryExtension,
+    ) -> Result<&'a GalleryVersion, HelmError> {
+        let mut versions = ext_data.versions.iter().filter(|x| self.match_platform(x));
+        let ver_data = match (self.get_version_req(), &self.version) {
+            // the pre-releases are skipped for the ranges
+            (Some(req), _) => versions
+                .filter(|x| !x.is_pre_release())
+                .filter_map(|x| Version::parse(&x.version).ok().map(|v| (v, x)))
+                .filter(|(v, _)| req.matches(v))
+                .max_by(|a, b| a.0.cmp(&b.0))
+                .map(|x| x.1),
+            (None, Some(version)) => versions.find(|x| &x.version == version),
+            (None, None) => versions.next(),
         };
-        self.check_platform()?;
-        let (version, platform) = match &self.version {
-            Some(v) => (v.clone(), self.platform.clone()),
-            None => {
-                let (v, p) = self.query_version()?;
-                let v = match v {
-                    Some(v) => v,
-                    None => {
-                        let ext_name = self.get_extension_name();
-                        return Err(format!("query version for {} failed", &ext_name).into());
-                    }
-                };
-                (v, p.clone())
+        ver_data.ok_or(HelmError::VersionNotFound(self.get_extension_name()))
+    }
+
+    fn match_platform(&self, ver_data: &GalleryVersion) -> bool {
+        match (self.platform.as_ref(), ver_data.target_platform.as_ref()) {
+            (Some(v1), Some(v2)) => v1 == v2,
+            (None, Some(_)) => false,
+            _ => true,
+        }
+    }
+
+    pub fn check_outdated(
+        &self,
+        ext_data: &GalleryExtension,
+        engine_version: Option<&str>,
+    ) -> Outdated {
+        let versions: Vec<&GalleryVersion> = ext_data
+            .versions
+            .iter()
+            .filter(|x| self.match_platform(x))
+            .collect();
+        let current = match self.get_version_req() {
+            Some(_) => self.select_version(ext_data).ok(),
+            None => versions
+                .iter()
+                .find(|x| self.version.as_ref() == Some(&x.version))
+                .copied(),
+        };
+        let latest = versions.iter().find(|x| {
+            if x.is_pre_release() {
+                return false;
+            }
+            match (engine_version, x.get_engine()) {
+                (Some(v1), Some(v2)) => is_engine_compatible(v2, v1),
+                _ => true,
             }
+        });
+        let get_engine = |x: &GalleryVersion| x.get_engine().map(str::to_string);
+        Outdated {
+            id: get_extension_name(
+                &self.publisher,
+                &self.package,
+                None,
+                self.platform.as_deref(),
+            ),
+            current: current
+                .map(|x| x.version.clone())
+                .or_else(|| self.version.clone()),
+            current_engine: current.and_then(get_engine),
+            latest: latest.map(|x| x.version.clone()),
+            latest_engine: latest.and_then(|x| get_engine(x)),
+        }
+    }
+}
+
+impl Diagnostic {
+    fn from_error(source: &str, line: Option<usize>, entry: &str, error: HelmError) -> Diagnostic {
+        Diagnostic {
+            source: source.to_string(),
+            line,
+            entry: entry.to_string(),
+            message: get_entry_message(error),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}:{}: {}: {}",
+                self.source, line, self.message, self.entry
+            ),
+            None => write!(f, "{}: {}: {}", self.source, self.message, self.entry),
+        }
+    }
+}
+
+impl Outdated {
+    pub fn has_update(&self) -> bool {
+        match (&self.current, &self.latest) {
+            (Some(v1), Some(v2)) => compare_version(v2, v1) == Ordering::Greater,
+            _ => false,
+        }
+    }
+
+    pub fn engine_changed(&self) -> bool {
+        self.has_update() && self.current_engine != self.latest_engine
+    }
+}
+
+impl Helm {
+    pub fn resolve(&self, extension: &Extension) -> Result<Extension, HelmError> {
+        let ext_data = match extension.is_resolved() {
+            true => None,
+            false => Some(self.query_extension(&extension.publisher, &extension.package, None)?),
         };
-        let ext_name = get_extension_name(
-            &self.publisher,
-            &self.package,
-            Some(&version),
-            platform.as_deref(),
-        );
+        extension.resolve_from(ext_data.as_ref())
+    }
+
+    pub fn download_extension(
+        &self,
+        extension: &Extension,
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Result<bool, HelmError> {
+        self.download_resolved(&self.resolve(extension)?, download_dir, cached)
+    }
+
+    pub fn download_resolved(
+        &self,
+        extension: &Extension,
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Result<bool, HelmError> {
+        let cached = cached.unwrap_or(true);
+        let version = match extension.get_resolved_version() {
+            Some(v) => v,
+            None => return Err(HelmError::NotResolved(extension.get_extension_name())),
+        };
+        let ext_name = extension.get_extension_name();
         info!("downloading extension {}", &ext_name);
         let output_file = format!("{}{}{}.vsix", download_dir, MAIN_SEPARATOR, &ext_name);
         if cached && Path::new(&output_file).exists() {
@@ -108,64 +364,390 @@ impl Extension {
             return Ok(false);
         }
         fs::create_dir_all(download_dir)?;
-        let result = download_extension(
-            &self.publisher,
-            &self.package,
-            &version,
-            platform.as_deref(),
-            &output_file,
-            cached,
+        let cache_key = cache::get_extension_key(
+            &extension.publisher,
+            &extension.package,
+            version,
+            extension.platform.as_deref(),
         );
-        match result {
-            Ok(()) => Ok(true),
-            Err(e) => Err(e),
-        }
-    }
-
-    pub fn query_version(&self) -> Result<(Option<String>, Option<String>), Box<dyn Error>> {
-        let all_data = query_extension(&self.publisher, &self.package, None)?;
-        let (version, platform): (Option<String>, Option<String>) = all_data
-            .get("versions")
-            .and_then(|x| x.as_array())
-            .map_or((None, None), |x| {
-                for ver_data in x {
-                    let version = ver_data.get("version").and_then(|x| x.as_str());
-                    let platform = ver_data.get("targetPlatform").and_then(|x| x.as_str());
-                    let version = match (self.version.as_ref(), version) {
-                        (Some(v1), Some(v2)) => {
-                            if v1 != v2 {
-                                continue;
-                            }
-                            Some(v2.to_string())
-                        }
-                        (_, None) => {
-                            continue;
-                        }
-                        (_, Some(v2)) => Some(v2.to_string()),
+        // a forced download bypasses the shared cache too, the fresh file replaces the entry
+        if let Some(cache) = self.get_cache().filter(|_| cached) {
+            if cache.fetch(&cache_key, Path::new(&output_file))?.is_some() {
+                return Ok(false);
+            }
+        }
+        self.get_registry()
+            .download_extension(extension, &output_file, cached)?;
+        if let Some(cache) = self.get_cache() {
+            cache.store(&cache_key, Path::new(&output_file))?;
+        }
+        Ok(true)
+    }
+
+    pub fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError> {
+        self.get_registry()
+            .query_extension(publisher, package, flags)
+    }
+
+    pub fn query_extensions(
+        &self,
+        ext_ids: &[String],
+        flags: Option<usize>,
+    ) -> Result<HashMap<String, GalleryExtension>, HelmError> {
+        self.get_registry().query_extensions(ext_ids, flags)
+    }
+
+    pub fn search_extension(
+        &self,
+        text: &str,
+        sort_by: &str,
+        page_number: usize,
+        page_size: usize,
+    ) -> Result<(Vec<GalleryExtension>, usize), HelmError> {
+        self.get_registry()
+            .search_extension(text, sort_by, page_number, page_size)
+    }
+
+    pub fn resolve_extensions(&self, extensions: &[Extension]) -> (Vec<Extension>, Vec<String>) {
+        let ext_ids: Vec<String> = extensions
+            .iter()
+            .filter(|x| !x.is_resolved())
+            .map(|x| x.get_extension_id())
+            .collect();
+        let all_data = match ext_ids.is_empty() {
+            true => HashMap::new(),
+            false => self.query_extensions(&ext_ids, None).unwrap_or_else(|e| {
+                error!("caught error: {:#?}", e);
+                HashMap::new()
+            }),
+        };
+        let mut resolved: Vec<Extension> = vec![];
+        let mut failed: Vec<String> = vec![];
+        for extension in extensions {
+            let ext_data = all_data.get(&extension.get_extension_id().to_lowercase());
+            match extension.resolve_from(ext_data) {
+                Ok(v) => resolved.push(v),
+                Err(e) => {
+                    warn!(
+                        "resolve extension {} failed: {}",
+                        extension.get_extension_name(),
+                        e
+                    );
+                    failed.push(extension.get_extension_name());
+                }
+            }
+        }
+        (resolved, failed)
+    }
+
+    pub fn download_extensions(
+        &self,
+        extensions: &[Extension],
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Vec<String> {
+        let next_index = AtomicUsize::new(0);
+        let failed: Mutex<Vec<String>> = Mutex::new(vec![]);
+        let counter = Counter::new("extensions", extensions.len(), self.get_progress());
+        thread::scope(|scope| {
+            for _ in 0..self.get_concurrency() {
+                scope.spawn(|| loop {
+                    let index = next_index.fetch_add(1, AtomicOrdering::SeqCst);
+                    let extension = match extensions.get(index) {
+                        Some(v) => v,
+                        None => break,
                     };
-                    match (self.platform.as_ref(), platform) {
-                        (Some(v1), Some(v2)) => {
-                            if v1 != v2 {
-                                continue;
-                            }
-                            return (version, Some(v2.to_string()));
-                        }
-                        (None, Some(_)) => {
-                            continue;
-                        }
-                        _ => {
-                            return (version, None);
+                    let result = self.download_extension(extension, download_dir, cached);
+                    let success = match result {
+                        Ok(_) => true,
+                        Err(e) => {
+                            error!("caught error: {:#?}", e);
+                            false
                         }
+                    };
+                    let ext_name = extension.get_extension_name();
+                    counter.inc(&ext_name, success);
+                    if !success {
+                        warn!("download extension {} failed", &ext_name);
+                        failed.lock().unwrap().push(ext_name);
                     }
-                }
-                return (None, None);
+                });
+            }
+        });
+        counter.finish();
+        let mut failed = failed.into_inner().unwrap();
+        failed.sort();
+        failed
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncHelm {
+    pub async fn resolve(&self, extension: &Extension) -> Result<Extension, HelmError> {
+        let ext_data = match extension.is_resolved() {
+            true => None,
+            false => Some(
+                self.query_extension(&extension.publisher, &extension.package, None)
+                    .await?,
+            ),
+        };
+        extension.resolve_from(ext_data.as_ref())
+    }
+
+    pub async fn download_extension(
+        &self,
+        extension: &Extension,
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Result<bool, HelmError> {
+        let extension = self.resolve(extension).await?;
+        self.download_resolved(&extension, download_dir, cached)
+            .await
+    }
+
+    pub async fn download_resolved(
+        &self,
+        extension: &Extension,
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Result<bool, HelmError> {
+        let cached = cached.unwrap_or(true);
+        let version = match extension.get_resolved_version() {
+            Some(v) => v,
+            None => return Err(HelmError::NotResolved(extension.get_extension_name())),
+        };
+        let ext_name = extension.get_extension_name();
+        info!("downloading extension {}", &ext_name);
+        let output_file = format!("{}{}{}.vsix", download_dir, MAIN_SEPARATOR, &ext_name);
+        if cached && Path::new(&output_file).exists() {
+            info!("{output_file} already exists, skip downloading");
+            return Ok(false);
+        }
+        tokio::fs::create_dir_all(download_dir).await?;
+        let cache_key = cache::get_extension_key(
+            &extension.publisher,
+            &extension.package,
+            version,
+            extension.platform.as_deref(),
+        );
+        if let Some(cache) = self.get_cache().filter(|_| cached) {
+            let entry = cache
+                .fetch_async(&cache_key, Path::new(&output_file))
+                .await?;
+            if entry.is_some() {
+                return Ok(false);
+            }
+        }
+        self.get_registry()
+            .download_extension(extension, &output_file, cached)
+            .await?;
+        if let Some(cache) = self.get_cache() {
+            cache
+                .store_async(&cache_key, Path::new(&output_file))
+                .await?;
+        }
+        Ok(true)
+    }
+
+    pub async fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError> {
+        self.get_registry()
+            .query_extension(publisher, package, flags)
+            .await
+    }
+
+    pub async fn download_extensions(
+        &self,
+        extensions: &[Extension],
+        download_dir: &str,
+        cached: Option<bool>,
+    ) -> Vec<String> {
+        let semaphore = Arc::new(Semaphore::new(self.get_concurrency()));
+        let counter = Counter::new("extensions", extensions.len(), self.get_progress());
+        let mut tasks = JoinSet::new();
+        for extension in extensions {
+            let helm = self.clone();
+            let extension = extension.clone();
+            let download_dir = download_dir.to_string();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = helm
+                    .download_extension(&extension, &download_dir, cached)
+                    .await;
+                (extension.get_extension_name(), result)
             });
-        if let None = version {
-            let ext_name = get_extension_name(&self.publisher, &self.package, None, None);
-            let message = format!("query extension {} for version failed", ext_name);
-            Err(message.into())
-        } else {
-            Ok((version, platform))
+        }
+        let mut failed: Vec<String> = vec![];
+        while let Some(joined) = tasks.join_next().await {
+            let (ext_name, result) = match joined {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("caught error: {:#?}", e);
+                    continue;
+                }
+            };
+            let success = match result {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("caught error: {:#?}", e);
+                    false
+                }
+            };
+            counter.inc(&ext_name, success);
+            if !success {
+                warn!("download extension {} failed", &ext_name);
+                failed.push(ext_name);
+            }
+        }
+        counter.finish();
+        failed.sort();
+        failed
+    }
+}
+
+pub(crate) static GALLERY_HEADERS: [(&str, &str); 3] = [
+    ("Content-Type", "application/json"),
+    ("Accept", "application/json;api-version=3.0-preview.1"),
+    ("User-Agent", "Offline VSIX/1.0"),
+];
+
+// the flags of the extension info queries, with the versions, files and properties
+pub(crate) static QUERY_FLAGS: usize = 0xD5;
+
+pub(crate) fn get_gallery_payload(filters: &json_value::Value, flags: usize) -> json_value::Value {
+    let payload = json!({
+        "flags": flags,
+        "filters": filters,
+    });
+    debug!("query gallery: {}", &payload);
+    payload
+}
+
+pub(crate) fn get_extension_filters(ext_name: &str) -> json_value::Value {
+    json!([{
+        "criteria": [{"filterType": 7, "value": ext_name}],
+        "pageNumber": 1,
+        "pageSize": 10,
+    }])
+}
+
+pub(crate) fn get_gallery_result(
+    data: GalleryResponse,
+    query_url: &str,
+) -> Result<GalleryResult, HelmError> {
+    match data.results.into_iter().next() {
+        Some(val) => Ok(val),
+        None => Err(HelmError::InvalidResponse {
+            url: query_url.to_string(),
+            message: String::from("no results found"),
+        }),
+    }
+}
+
+pub(crate) fn get_first_extension(
+    data: GalleryResult,
+    ext_name: String,
+) -> Result<GalleryExtension, HelmError> {
+    match data.extensions.into_iter().next() {
+        Some(val) => Ok(val),
+        None => Err(HelmError::ExtensionNotFound(ext_name)),
+    }
+}
+
+pub(crate) fn get_vsix_url(
+    download_url: Option<&str>,
+    extension: &Extension,
+) -> Result<String, HelmError> {
+    let version = match extension.get_resolved_version() {
+        Some(v) => v,
+        None => return Err(HelmError::NotResolved(extension.get_extension_name())),
+    };
+    // a configured download url takes precedence over the asset url from the gallery
+    let download_url = match (download_url, extension.download_url.as_deref()) {
+        (None, Some(v)) => v.to_string(),
+        (v, _) => {
+            let download_url = v.unwrap_or(DOWNLOAD_URL);
+            let download_url = download_url.replace("{publisher}", &extension.publisher);
+            let download_url = download_url.replace("{package}", &extension.package);
+            let download_url = download_url.replace("{version}", version);
+            match &extension.platform {
+                Some(val) => format!("{}?targetPlatform={}", download_url, val),
+                None => download_url,
+            }
+        }
+    };
+    Ok(download_url)
+}
+
+pub(crate) fn decode_vsix(
+    body_file: &str,
+    output_file: &str,
+    headers: &HeaderMap,
+) -> Result<(), HelmError> {
+    let encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    // replace the file by renaming, it may be hard linked from the cache
+    if encoding.contains("gzip") {
+        let temp_file = utils::get_temp_path(Path::new(output_file));
+        let result = File::create(&temp_file).and_then(|mut f_o| {
+            let mut gz = GzDecoder::new(BufReader::new(File::open(body_file)?));
+            io::copy(&mut gz, &mut f_o)?;
+            f_o.sync_all()?;
+            fs::rename(&temp_file, output_file)
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_file);
+            return Err(HelmError::from(e).context(format!("decode {} failed", body_file)));
+        }
+        fs::remove_file(body_file)?;
+    } else {
+        fs::rename(body_file, output_file)?;
+    }
+    Ok(())
+}
+
+// the entry or file is shown apart, keep the message and the causes
+fn get_entry_message(error: HelmError) -> String {
+    match error {
+        HelmError::InvalidExtension { message, .. } => message,
+        HelmError::InvalidVsix {
+            message,
+            source: Some(source),
+            ..
+        } => format!("{}: {}", message, error::report(source.as_ref())),
+        HelmError::InvalidVsix { message, .. } => message,
+        e => error::report(&e),
+    }
+}
+
+pub fn compare_version(v1: &str, v2: &str) -> Ordering {
+    match (Version::parse(v1), Version::parse(v2)) {
+        (Ok(v1), Ok(v2)) => v1.cmp(&v2),
+        _ => v1.cmp(v2),
+    }
+}
+
+pub fn is_engine_compatible(engine: &str, engine_version: &str) -> bool {
+    match (VersionReq::parse(engine), Version::parse(engine_version)) {
+        (Ok(req), Ok(version)) => req.matches(&version),
+        _ => {
+            debug!(
+                "unable to check engine {} against {}",
+                engine, engine_version
+            );
+            true
         }
     }
 }
@@ -192,145 +774,146 @@ pub fn query_extension(
     publisher: &str,
     package: &str,
     flags: Option<usize>,
-) -> Result<json_value::Value, Box<dyn Error>> {
-    let flags = match flags {
-        Some(flags) => flags,
-        None => 0x55,
-    };
-    let ext_name = get_extension_name(publisher, package, None, None);
-    let filters = json!([{
-        "criteria": [{"filterType": 7, "value": ext_name}],
-        "pageNumber": 1,
-        "pageSize": 10,
-    }]);
-    let payload = json!({
-        "flags": flags,
-        "filters":filters,
-    });
-    let client = reqwest::blocking::Client::new();
-    let mut request = client.post(QUERY_URL);
-    let headers = vec![
-        ("Content-Type", "application/json"),
-        ("Accept", "application/json;api-version=3.0-preview.1"),
-        ("User-Agent", "Offline VSIX/1.0"),
-    ];
-    for (key, val) in headers {
-        request = request.header(key, val);
-    }
-    request = request.json(&payload);
-    let response = request.send()?;
-    let response = response.error_for_status();
-    let data = response
-        .and_then(|x| x.json().map(|x: json_value::Value| x))
-        .context(format!("query extension {} info failed", &ext_name))?;
-    let data = data
-        .get("results")
-        .and_then(|x| x.get(0))
-        .and_then(|x| x.get("extensions"))
-        .and_then(|x| x.get(0));
-    match data {
-        Some(val) => Ok(val.clone()),
-        None => Err("no data found in query response".into()),
+) -> Result<GalleryExtension, HelmError> {
+    Helm::from_config()?.query_extension(publisher, package, flags)
+}
+
+pub fn query_extensions(
+    ext_ids: &[String],
+    flags: Option<usize>,
+) -> Result<HashMap<String, GalleryExtension>, HelmError> {
+    Helm::from_config()?.query_extensions(ext_ids, flags)
+}
+
+pub fn resolve_extensions(extensions: &[Extension]) -> (Vec<Extension>, Vec<String>) {
+    match Helm::from_config() {
+        Ok(helm) => helm.resolve_extensions(extensions),
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            (
+                vec![],
+                extensions.iter().map(|x| x.get_extension_name()).collect(),
+            )
+        }
     }
 }
 
+pub fn search_extension(
+    text: &str,
+    sort_by: &str,
+    page_number: usize,
+    page_size: usize,
+) -> Result<(Vec<GalleryExtension>, usize), HelmError> {
+    Helm::from_config()?.search_extension(text, sort_by, page_number, page_size)
+}
+
 pub fn download_extension(
     publisher: &str,
     package: &str,
     version: &str,
     platform: Option<&str>,
+    download_url: Option<&str>,
     output_file: &str,
     cached: bool,
-) -> Result<(), Box<dyn Error>> {
-    let ext_name = get_extension_name(publisher, package, Some(version), platform);
-    let download_url = DOWNLOAD_URL.replacen("{}", publisher, 1);
-    let download_url = download_url.replacen("{}", package, 1);
-    let mut download_url = download_url.replacen("{}", version, 1);
-    if let Some(val) = platform {
-        download_url = format!("{}?targetPlatform={}", download_url, val);
-    }
-    debug!("downloading {}:\nURL: {}", &ext_name, &download_url);
-    let head_file = format!("{}.header", output_file);
-    let body_file = format!("{}.downloading", output_file);
-    let mut curl_args = vec!["-fSL"];
-    if cached {
-        curl_args.extend(["-C", "-"]);
-    }
-    curl_args.extend([
-        &download_url,
-        "-o",
-        body_file.as_str(),
-        "-D",
-        head_file.as_str(),
-    ]);
-    let prog_name = String::from("curl");
-    let prog_text = format!("{} {}", prog_name, curl_args.join(" "));
-    debug!("exec command {}", prog_text);
-    let status = Command::new(&prog_name).args(&curl_args).status();
-    match status {
-        Ok(status) => {
-            if !status.success() {
-                return Err(format!("exec command {} failed", prog_text).into());
-            }
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
-    let encoding = match utils::parse_http_header_content_encoding(&head_file) {
-        None => String::from(""),
-        Some(v) => v,
+) -> Result<(), HelmError> {
+    let extension = Extension {
+        download_url: download_url.map(str::to_string),
+        ..Extension::new(publisher, package, Some(version), platform)
     };
-    let mut f_i = File::open(&body_file)?;
-    let mut data = vec![];
-    f_i.read_to_end(&mut data)?;
-    if encoding.contains("gzip") {
-        let mut gz = GzDecoder::new(&data[..]);
-        let mut decoded = vec![];
-        gz.read_to_end(&mut decoded)?;
-        data = decoded;
-    }
-    let mut f_o = File::create(&output_file)?;
-    f_o.write_all(&data)?;
-    fs::remove_file(body_file)?;
-    fs::remove_file(head_file)?;
-    Ok(())
+    Helm::from_config()?
+        .get_registry()
+        .download_extension(&extension, output_file, cached)
 }
 
-pub fn list_extensions(extensions: &Vec<String>) -> Vec<Extension> {
-    fn strip_suffix<'a>(line: &'a str, mark: &str) -> (&'a str, Option<&'a str>) {
-        let pos = line.find(mark);
-        match pos {
-            Some(pos) => (&line[0..pos], Some(&line[pos + mark.len()..])),
-            None => (line, None),
-        }
+fn strip_suffix<'a>(line: &'a str, mark: &str) -> (&'a str, Option<&'a str>) {
+    let pos = line.find(mark);
+    match pos {
+        Some(pos) => (&line[0..pos], Some(&line[pos + mark.len()..])),
+        None => (line, None),
     }
-    fn parse_ext_line(ext_line: &str) -> Option<Extension> {
-        let ext_line = ext_line.trim();
-        let ext_line = match ext_line.strip_suffix(".vsix") {
-            Some(v) => v,
-            None => ext_line,
-        };
-        let (ext_prefix, platform) = strip_suffix(ext_line, "=");
-        let (ext_prefix, version) = strip_suffix(ext_prefix, "@");
-        let (publisher, package) = strip_suffix(ext_prefix, ".");
-        let package = package?;
-        Some(Extension {
-            package: package.to_string(),
-            publisher: publisher.to_string(),
-            platform: platform.map(str::to_string),
-            version: version.map(str::to_string),
-        })
+}
+fn is_version_range(version: &str) -> bool {
+    version.contains(['^', '~', '<', '>', '=', '*'])
+}
+
+fn is_valid_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphanumeric() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        _ => false,
     }
-    fn parse_ext_dict(ext_dict: &json_value::Value) -> Option<Extension> {
-        let identifier = ext_dict.get("identifier")?;
-        let ext_name = identifier.get("id")?.as_str()?;
+}
+
+pub fn parse_ext_line(ext_line: &str) -> Option<Extension> {
+    try_parse_ext_line(ext_line).ok()
+}
+
+// the target platforms of the marketplace with their descriptions
+static PLATFORMS: [(&str, &str); 11] = [
+    ("win32-x64", "Windows x64"),
+    ("win32-ia32", "Windows ia32"),
+    ("win32-arm64", "Windows ARM"),
+    ("linux-x64", "Linux x64"),
+    ("linux-arm64", "Linux ARM64"),
+    ("linux-armhf", "Linux ARM32"),
+    ("darwin-x64", "macOS Intel"),
+    ("darwin-arm64", "macOS Apple Silicon"),
+    ("alpine-x64", "Alpine Linux 64 bit"),
+    ("web", "Web"),
+    ("alpine-arm64", "Alpine Linux ARM64"),
+];
+
+fn is_platform(platform: &str) -> bool {
+    PLATFORMS.iter().any(|x| x.0 == platform)
+}
+
+pub fn try_parse_ext_line(ext_line: &str) -> Result<Extension, HelmError> {
+    let ext_line = ext_line.trim();
+    let ext_line = match ext_line.strip_suffix(".vsix") {
+        Some(v) => v,
+        None => ext_line,
+    };
+    // only a known platform is split off, the "=" may be a part of the version like "@>= 1.2"
+    let (ext_prefix, platform) = match ext_line.rsplit_once("=") {
+        Some((prefix, platform)) if is_platform(platform) => (prefix, Some(platform)),
+        _ => (ext_line, None),
+    };
+    let (ext_prefix, version) = strip_suffix(ext_prefix, "@");
+    let (publisher, package) = strip_suffix(ext_prefix, ".");
+    let package = package.ok_or_else(|| HelmError::InvalidExtension {
+        entry: ext_line.to_string(),
+        message: "expect <publisher>.<package>[@version][=platform]".to_string(),
+    })?;
+    let ext = Extension {
+        package: package.to_string(),
+        publisher: publisher.to_string(),
+        platform: platform.map(str::to_string),
+        version: version.map(str::to_string),
+        download_url: None,
+    };
+    ext.validate()?;
+    Ok(ext)
+}
+pub fn list_extensions(
+    extensions: &Vec<String>,
+) -> Result<(Vec<Extension>, Vec<Diagnostic>), HelmError> {
+    fn parse_ext_dict(ext_dict: &json_value::Value) -> Result<Extension, HelmError> {
+        let ext_name = ext_dict
+            .get("identifier")
+            .and_then(|x| x.get("id"))
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| HelmError::InvalidExtension {
+                entry: ext_dict.to_string(),
+                message: "no identifier.id".to_string(),
+            })?;
         let version = match ext_dict.get("version") {
             Some(ver) => ver.as_str().map(str::to_string),
             None => None,
         };
         let mut platform = Some(ext_dict);
-        for key in vec!["metadata", "targetPlatform"] {
+        for key in ["metadata", "targetPlatform"] {
             platform = match platform {
                 Some(value) => match value.get(key) {
                     Some(value) => Some(value),
@@ -346,54 +929,710 @@ pub fn list_extensions(extensions: &Vec<String>) -> Vec<Extension> {
             },
             None => None,
         };
-        let ext = parse_ext_line(&String::from(ext_name));
-        ext.map(|x| Extension {
+        let ext = Extension {
             platform,
             version,
-            ..x
-        })
+            ..try_parse_ext_line(ext_name)?
+        };
+        ext.validate()?;
+        Ok(ext)
     }
     let mut result: Vec<Extension> = vec![];
+    let mut diagnostics: Vec<Diagnostic> = vec![];
     for extension in extensions {
         let ext_path = shellexpand::full(extension);
         let ext_path = ext_path.map_or(extension.clone(), |x| x.to_string());
         if !Path::new(&ext_path).exists() {
-            let ext = parse_ext_line(&ext_path);
-            if let Some(ext) = ext {
-                result.push(ext)
+            match try_parse_ext_line(&ext_path) {
+                Ok(ext) => result.push(ext),
+                Err(e) => {
+                    diagnostics.push(Diagnostic::from_error("command line", None, &ext_path, e))
+                }
             }
             continue;
         }
+        if Path::new(&ext_path).is_dir() {
+            let (extensions, vsix_diagnostics) = list_vsix_dir(&ext_path)?;
+            result.extend(extensions);
+            diagnostics.extend(vsix_diagnostics);
+            continue;
+        }
         let content = fs::read_to_string(&ext_path)
-            .expect(format!("read file {} failed", &ext_path).as_str());
-        if vec!["[", "{"].iter().any(|x| content.starts_with(x)) {
-            let data: json_value::Value = json_from_str(&content)
-                .expect(format!("parse json failed from {}", ext_path).as_str());
-            if let Some(data) = data.as_array() {
-                for item in data {
-                    let ext = parse_ext_dict(item);
-                    if let Some(ext) = ext {
-                        result.push(ext);
-                    }
-                }
-            } else if data.is_object() {
-                let ext = parse_ext_dict(&data);
-                if let Some(ext) = ext {
-                    result.push(ext);
+            .map_err(|e| HelmError::from(e).context(format!("read {} failed", &ext_path)))?;
+        if ["[", "{"].iter().any(|x| content.starts_with(x)) {
+            let data: json_value::Value = json_from_str(&content).map_err(|e| {
+                HelmError::from(e).context(format!("parse json {} failed", &ext_path))
+            })?;
+            // the array entries are reported as <file>[<index>]
+            let items: Vec<(String, &json_value::Value)> = match &data {
+                json_value::Value::Array(v) => v
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| (format!("{}[{}]", &ext_path, i), x))
+                    .collect(),
+                _ => vec![(ext_path.clone(), &data)],
+            };
+            for (source, item) in items {
+                match parse_ext_dict(item) {
+                    Ok(ext) => result.push(ext),
+                    Err(e) => diagnostics.push(Diagnostic::from_error(
+                        &source,
+                        None,
+                        &item.to_string(),
+                        e,
+                    )),
                 }
-            } else {
-                continue;
             }
         } else {
-            for line in content.split("\n") {
-                let ext = parse_ext_line(line);
-                if let Some(ext) = ext {
-                    result.push(ext);
+            for (index, line) in content.split("\n").enumerate() {
+                // the text after # is a comment
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match try_parse_ext_line(line) {
+                    Ok(ext) => result.push(ext),
+                    Err(e) => diagnostics.push(Diagnostic::from_error(
+                        &ext_path,
+                        Some(index + 1),
+                        line,
+                        e,
+                    )),
                 }
             }
         }
     }
-    result.sort_by(|a, b| a.get_extension_name().cmp(&b.get_extension_name()));
+    result.sort_by_key(|a| a.get_extension_name());
     result.dedup_by_key(|x| x.get_extension_name());
-    result
+    Ok((result, diagnostics))
+}
+
+// the vsix files are identified by their manifests, the renamed files are listed correctly
+fn list_vsix_dir(vsix_dir: &str) -> Result<(Vec<Extension>, Vec<Diagnostic>), HelmError> {
+    let mut result: Vec<Extension> = vec![];
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    let entries = fs::read_dir(vsix_dir)
+        .map_err(|e| HelmError::from(e).context(format!("read {} failed", vsix_dir)))?;
+    for entry in entries {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if !path.is_file() || !file_name.ends_with(".vsix") {
+            continue;
+        }
+        match vsix::read_vsix(&path) {
+            Ok(package) => result.push(package.to_extension()),
+            Err(e) => diagnostics.push(Diagnostic::from_error(vsix_dir, None, file_name, e)),
+        }
+    }
+    Ok((result, diagnostics))
+}
+
+// the resolved extensions for other tools: "install" for the code --install-extension lines,
+// "recommendations" for .vscode/extensions.json, "extensions-json" for the format read by
+// list_extensions, "devcontainer" for the customizations.vscode.extensions snippet
+pub fn emit_extensions(
+    extensions: &[Extension],
+    format: &str,
+    download_dir: &str,
+) -> Result<String, HelmError> {
+    // the platform variants of an extension share the id
+    let ext_ids: BTreeSet<String> = extensions.iter().map(|x| x.get_extension_id()).collect();
+    let data = match format {
+        "install" => {
+            let lines: Vec<String> = extensions
+                .iter()
+                .map(|x| {
+                    let vsix_file =
+                        Path::new(download_dir).join(format!("{}.vsix", x.get_extension_name()));
+                    let vsix_file = utils::shell_quote(&vsix_file.to_string_lossy());
+                    format!("code --install-extension {}\n", vsix_file)
+                })
+                .collect();
+            return Ok(lines.concat());
+        }
+        "recommendations" => json!({ "recommendations": ext_ids }),
+        "extensions-json" => {
+            let items: Vec<json_value::Value> = extensions
+                .iter()
+                .map(|x| {
+                    let mut item = json!({
+                        "identifier": {"id": x.get_extension_id()},
+                        "version": x.version,
+                    });
+                    if let Some(platform) = &x.platform {
+                        item["metadata"] = json!({ "targetPlatform": platform });
+                    }
+                    item
+                })
+                .collect();
+            json!(items)
+        }
+        "devcontainer" => {
+            // the platform variants of a version are installed by one entry
+            let items: BTreeSet<String> = extensions
+                .iter()
+                .map(|x| get_extension_name(&x.publisher, &x.package, x.version.as_deref(), None))
+                .collect();
+            json!({ "customizations": { "vscode": { "extensions": items } } })
+        }
+        _ => {
+            return Err(HelmError::InvalidOption {
+                name: "emit format".to_string(),
+                value: format.to_string(),
+            })
+        }
+    };
+    Ok(format!("{}\n", serde_json::to_string_pretty(&data)?))
+}
+
+pub fn download_extensions(
+    extensions: &[Extension],
+    download_dir: &str,
+    cached: Option<bool>,
+    concurrency: usize,
+) -> Vec<String> {
+    match HelmBuilder::from_config().concurrency(concurrency).build() {
+        Ok(helm) => helm.download_extensions(extensions, download_dir, cached),
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            extensions.iter().map(|x| x.get_extension_name()).collect()
+        }
+    }
+}
+
+// the vsix files in the download dir named by get_extension_name
+fn list_downloaded(download_dir: &str) -> Result<Vec<(Extension, PathBuf)>, HelmError> {
+    let mut result: Vec<(Extension, PathBuf)> = vec![];
+    for entry in fs::read_dir(download_dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if !path.is_file() || !file_name.ends_with(".vsix") {
+            continue;
+        }
+        match parse_ext_line(file_name) {
+            Some(ext) if ext.version.is_some() => result.push((ext, path)),
+            _ => debug!("skip unrecognized file {}", path.display()),
+        }
+    }
+    Ok(result)
+}
+
+// the extensions without a pinned version take the newest version in the download dir
+pub fn pin_downloaded_versions(
+    extensions: &[Extension],
+    download_dir: &str,
+) -> Result<Vec<Extension>, HelmError> {
+    if !Path::new(download_dir).is_dir() {
+        return Ok(extensions.to_vec());
+    }
+    let downloaded = list_downloaded(download_dir)?;
+    let pinned = extensions
+        .iter()
+        .map(|x| {
+            if x.version.is_some() {
+                return x.clone();
+            }
+            let version = downloaded
+                .iter()
+                .map(|(ext, _)| ext)
+                .filter(|ext| {
+                    ext.get_extension_id()
+                        .eq_ignore_ascii_case(&x.get_extension_id())
+                        && ext.platform == x.platform
+                })
+                .filter_map(|ext| ext.version.as_deref())
+                .max_by(|a, b| compare_version(a, b));
+            Extension {
+                version: version.map(str::to_string),
+                ..x.clone()
+            }
+        })
+        .collect();
+    Ok(pinned)
+}
+
+pub fn prune_extensions(
+    download_dir: &str,
+    keep: usize,
+    referenced: Option<&Vec<Extension>>,
+    dry_run: bool,
+) -> Result<(Vec<String>, u64), HelmError> {
+    let mut groups: HashMap<String, Vec<(Extension, PathBuf)>> = HashMap::new();
+    for (ext, path) in list_downloaded(download_dir)? {
+        let key = get_extension_name(&ext.publisher, &ext.package, None, ext.platform.as_deref());
+        groups.entry(key).or_default().push((ext, path));
+    }
+    let mut removed: Vec<String> = vec![];
+    let mut reclaimed: u64 = 0;
+    for (_, mut files) in groups {
+        files.sort_by(|a, b| {
+            compare_version(
+                b.0.version.as_deref().unwrap(),
+                a.0.version.as_deref().unwrap(),
+            )
+        });
+        let references: Option<Vec<&Extension>> = referenced.map(|x| {
+            x.iter()
+                .filter(|r| {
+                    let ext = &files[0].0;
+                    r.publisher == ext.publisher
+                        && r.package == ext.package
+                        && (r.platform.is_none() || r.platform == ext.platform)
+                })
+                .collect()
+        });
+        for (index, (ext, path)) in files.iter().enumerate() {
+            let retained = match &references {
+                None => index < keep,
+                Some(references) => references.iter().any(|r| match r.get_version_req() {
+                    // the newest matched versions are kept, the first is the one select_version picks
+                    Some(req) => files
+                        .iter()
+                        .filter(|(x, _)| {
+                            let version = x.version.as_deref().and_then(|v| Version::parse(v).ok());
+                            version.is_some_and(|v| req.matches(&v))
+                        })
+                        .take(keep)
+                        .any(|(x, _)| x.version == ext.version),
+                    None => match &r.version {
+                        Some(_) => r.version == ext.version,
+                        None => index < keep,
+                    },
+                }),
+            };
+            if retained {
+                continue;
+            }
+            let path = path.to_string_lossy().to_string();
+            reclaimed += fs::metadata(&path)?.len();
+            if dry_run {
+                info!("would remove {}", &path);
+            } else {
+                info!("removing {}", &path);
+                fs::remove_file(&path)?;
+            }
+            removed.push(path);
+        }
+    }
+    removed.sort();
+    Ok((removed, reclaimed))
+}
+
+pub fn verify_extensions(download_dir: &str) -> Result<Vec<(PathBuf, String)>, HelmError> {
+    let mut corrupted: Vec<(PathBuf, String)> = vec![];
+    let mut total = 0;
+    for entry in fs::read_dir(download_dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if !path.is_file() || !file_name.ends_with(".vsix") {
+            continue;
+        }
+        total += 1;
+        match vsix::verify_vsix(&path) {
+            Ok(_) => debug!("{} is valid", path.display()),
+            Err(e) => {
+                let reason = get_entry_message(e);
+                warn!("{} is corrupted: {}", path.display(), &reason);
+                corrupted.push((path, reason));
+            }
+        }
+    }
+    corrupted.sort();
+    info!("verified {} files, {} corrupted", total, corrupted.len());
+    Ok(corrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gallery::{GalleryProperty, PROPERTY_PRE_RELEASE};
+    use crate::helm::make_test_helm;
+    use tempfile::TempDir;
+
+    fn make_download_dir(names: &[&str]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in names {
+            fs::write(dir.path().join(name), b"vsix").unwrap();
+        }
+        dir
+    }
+
+    fn list_names(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn prune(dir: &TempDir, keep: usize, referenced: Option<&[&str]>) -> Vec<String> {
+        let referenced: Option<Vec<Extension>> = referenced.map(|x| {
+            x.iter()
+                .map(|line| try_parse_ext_line(line).unwrap())
+                .collect()
+        });
+        let download_dir = dir.path().to_str().unwrap();
+        prune_extensions(download_dir, keep, referenced.as_ref(), false).unwrap();
+        list_names(dir)
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_versions() {
+        let dir = make_download_dir(&[
+            "p.n@1.0.0.vsix",
+            "p.n@1.10.0.vsix",
+            "p.n@1.9.0.vsix",
+            "p.m@0.1.0.vsix",
+            "notes.txt",
+        ]);
+        let names = prune(&dir, 1, None);
+        assert_eq!(names, ["notes.txt", "p.m@0.1.0.vsix", "p.n@1.10.0.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_keeps_count() {
+        let dir = make_download_dir(&["p.n@1.0.0.vsix", "p.n@1.1.0.vsix", "p.n@1.2.0.vsix"]);
+        let names = prune(&dir, 2, None);
+        assert_eq!(names, ["p.n@1.1.0.vsix", "p.n@1.2.0.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_groups_platforms() {
+        let dir = make_download_dir(&[
+            "p.n@1.0.0.vsix",
+            "p.n@1.1.0.vsix",
+            "p.n@1.0.0=linux-x64.vsix",
+            "p.n@1.2.0=linux-x64.vsix",
+            "p.n@1.1.0=win32-x64.vsix",
+        ]);
+        let names = prune(&dir, 1, None);
+        assert_eq!(
+            names,
+            [
+                "p.n@1.1.0.vsix",
+                "p.n@1.1.0=win32-x64.vsix",
+                "p.n@1.2.0=linux-x64.vsix"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prune_pinned_reference() {
+        let dir = make_download_dir(&["p.n@1.0.0.vsix", "p.n@1.1.0.vsix", "p.n@2.0.0.vsix"]);
+        let names = prune(&dir, 1, Some(&["p.n@1.1.0"]));
+        assert_eq!(names, ["p.n@1.1.0.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_unpinned_reference() {
+        let dir = make_download_dir(&["p.n@1.0.0.vsix", "p.n@2.0.0.vsix", "p.m@1.0.0.vsix"]);
+        let names = prune(&dir, 1, Some(&["p.n"]));
+        assert_eq!(names, ["p.n@2.0.0.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_platform_reference() {
+        let dir = make_download_dir(&[
+            "p.n@1.0.0.vsix",
+            "p.n@1.0.0=linux-x64.vsix",
+            "p.n@1.1.0=linux-x64.vsix",
+        ]);
+        let names = prune(&dir, 1, Some(&["p.n=linux-x64"]));
+        assert_eq!(names, ["p.n@1.1.0=linux-x64.vsix"]);
+        let dir = make_download_dir(&["p.n@1.0.0.vsix", "p.n@1.1.0=linux-x64.vsix"]);
+        let names = prune(&dir, 1, Some(&["p.n"]));
+        assert_eq!(names, ["p.n@1.0.0.vsix", "p.n@1.1.0=linux-x64.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_range_reference() {
+        let dir = make_download_dir(&[
+            "p.n@2024.4.0.vsix",
+            "p.n@2024.4.1.vsix",
+            "p.n@2024.6.0.vsix",
+            "p.n@2025.1.0.vsix",
+        ]);
+        let names = prune(&dir, 1, Some(&["p.n@^2024.4"]));
+        assert_eq!(names, ["p.n@2024.6.0.vsix"]);
+        let dir = make_download_dir(&["p.n@2024.4.0.vsix", "p.n@2024.4.1.vsix"]);
+        let names = prune(&dir, 2, Some(&["p.n@~2024.4"]));
+        assert_eq!(names, ["p.n@2024.4.0.vsix", "p.n@2024.4.1.vsix"]);
+    }
+
+    #[test]
+    fn test_prune_dry_run() {
+        let dir = make_download_dir(&["p.n@1.0.0.vsix", "p.n@1.1.0.vsix"]);
+        let download_dir = dir.path().to_str().unwrap();
+        let (removed, reclaimed) = prune_extensions(download_dir, 1, None, true).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].ends_with("p.n@1.0.0.vsix"));
+        assert_eq!(reclaimed, 4);
+        assert_eq!(list_names(&dir), ["p.n@1.0.0.vsix", "p.n@1.1.0.vsix"]);
+    }
+
+    #[test]
+    fn test_list_extensions_json_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("extensions.json");
+        let content = r#"[
+            {"identifier": {"id": "p.a"}, "version": "1.0.0"},
+            {"version": "1.0.0"},
+            {"identifier": {"id": "p.b"}}
+        ]"#;
+        fs::write(&file, content).unwrap();
+        let file = file.to_string_lossy().to_string();
+        let (extensions, diagnostics) = list_extensions(&vec![file.clone()]).unwrap();
+        let ids: Vec<String> = extensions.iter().map(|x| x.get_extension_id()).collect();
+        assert_eq!(ids, ["p.a", "p.b"]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].source, format!("{}[1]", file));
+        assert_eq!(diagnostics[0].line, None);
+        assert_eq!(diagnostics[0].message, "no identifier.id");
+    }
+
+    #[test]
+    fn test_list_vsix_dir() {
+        let dir = make_download_dir(&["broken.vsix", "notes.txt"]);
+        vsix::write_test_vsix(&dir.path().join("renamed.vsix"), vsix::TEST_MANIFEST, None);
+        let vsix_dir = dir.path().to_string_lossy().to_string();
+        let (extensions, diagnostics) = list_extensions(&vec![vsix_dir.clone()]).unwrap();
+        let names: Vec<String> = extensions.iter().map(|x| x.get_extension_name()).collect();
+        assert_eq!(names, ["pub.pkg@1.2.0=linux-x64"]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].source, vsix_dir);
+        assert_eq!(diagnostics[0].entry, "broken.vsix");
+        assert!(diagnostics[0].message.starts_with("open vsix failed: "));
+    }
+
+    fn parse_error(line: &str) -> String {
+        match try_parse_ext_line(line) {
+            Err(HelmError::InvalidExtension { message, .. }) => message,
+            v => panic!("expect InvalidExtension for {}, got {:?}", line, v),
+        }
+    }
+
+    #[test]
+    fn test_parse_ext_line_accepted() {
+        let cases = [
+            ("a.b", None, None),
+            ("a.b=linux-x64", None, Some("linux-x64")),
+            ("a.b@1.0.0", Some("1.0.0"), None),
+            ("a.b@1.0.0=linux-x64", Some("1.0.0"), Some("linux-x64")),
+            ("a.b@=1.0.0", Some("=1.0.0"), None),
+            ("a.b@>=1.2=linux-x64", Some(">=1.2"), Some("linux-x64")),
+            ("a.b@>= 1.2", Some(">= 1.2"), None),
+            ("a.b@^1", Some("^1"), None),
+            (
+                " pub-1.pkg-2@1.0.0-beta.1.vsix ",
+                Some("1.0.0-beta.1"),
+                None,
+            ),
+        ];
+        for (line, version, platform) in cases {
+            let ext = try_parse_ext_line(line).unwrap();
+            assert_eq!(ext.get_version(), version, "{}", line);
+            assert_eq!(ext.get_platform(), platform, "{}", line);
+        }
+        let ext = try_parse_ext_line("a.b@>=1.2=linux-x64").unwrap();
+        assert_eq!(ext.get_extension_id(), "a.b");
+        assert!(!ext.is_resolved());
+        assert!(ext.get_version_req().is_some());
+        let ext = try_parse_ext_line("a.b@=1.0.0").unwrap();
+        assert!(!ext.is_resolved());
+        assert!(try_parse_ext_line("a.b@1.0.0").unwrap().is_resolved());
+    }
+
+    #[test]
+    fn test_parse_ext_line_rejected() {
+        assert_eq!(
+            parse_error("ab"),
+            "expect <publisher>.<package>[@version][=platform]"
+        );
+        assert_eq!(
+            parse_error("a.b@1.0"),
+            "invalid version '1.0': expect <major>.<minor>.<patch>, or a range like ^1.0"
+        );
+        assert!(parse_error("a.b@1.0.x.y").starts_with("invalid version '1.0.x.y'"));
+        assert!(parse_error("a.b@>=1.x.y").starts_with("invalid version '>=1.x.y'"));
+        assert_eq!(parse_error("a_c.b"), "invalid publisher 'a_c'");
+        assert_eq!(parse_error("a.-b"), "invalid name '-b'");
+        // an unknown platform is left in the version or the name
+        assert!(parse_error("a.b@1.0.0=linux").starts_with("invalid version '1.0.0=linux'"));
+        assert_eq!(parse_error("a.b="), "invalid name 'b='");
+        let ext = Extension {
+            platform: Some(String::from("linux")),
+            ..try_parse_ext_line("a.b").unwrap()
+        };
+        assert!(matches!(
+            ext.validate(),
+            Err(HelmError::InvalidPlatform { .. })
+        ));
+        assert!(parse_ext_line("a.b@1.0").is_none());
+    }
+
+    fn resolve_version(helm: &Helm, line: &str) -> Result<String, HelmError> {
+        let ext = helm.resolve(&try_parse_ext_line(line).unwrap())?;
+        Ok(ext.get_version().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        let helm = make_test_helm(&[
+            "p.n@1.0.0",
+            "p.n@1.2.0",
+            "p.n@1.2.5",
+            "p.n@1.3.0",
+            "p.n@2.0.0",
+            "p.n@1.1.0=linux-x64",
+            "p.n@1.4.0=linux-x64",
+        ]);
+        let cases = [
+            ("p.n@^1", "1.3.0"),
+            ("p.n@~1.2", "1.2.5"),
+            ("p.n@>=1.0.0, <2.0.0", "1.3.0"),
+            ("p.n@>=1.0.0", "2.0.0"),
+            ("p.n@=1.2.0", "1.2.0"),
+            ("p.n@1.2.0", "1.2.0"),
+            ("p.n", "2.0.0"),
+            ("p.n@^1=linux-x64", "1.4.0"),
+            // the universal versions match any platform
+            ("p.n@~1.2=linux-x64", "1.2.5"),
+        ];
+        for (line, version) in cases {
+            assert_eq!(resolve_version(&helm, line).unwrap(), version, "{}", line);
+        }
+        for line in ["p.n@^3", "p.n@>1.4.0, <2.0.0=linux-x64"] {
+            assert!(
+                matches!(
+                    resolve_version(&helm, line),
+                    Err(HelmError::VersionNotFound(_))
+                ),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_select_version_skips_pre_release() {
+        let make_version = |version: &str, pre_release: bool| GalleryVersion {
+            version: version.to_string(),
+            properties: match pre_release {
+                true => vec![GalleryProperty {
+                    key: PROPERTY_PRE_RELEASE.to_string(),
+                    value: "true".to_string(),
+                }],
+                false => vec![],
+            },
+            ..GalleryVersion::default()
+        };
+        let ext_data = GalleryExtension {
+            versions: vec![
+                make_version("1.3.0", true),
+                make_version("1.3.0-beta.1", false),
+                make_version("1.2.0", false),
+                make_version("1.1.0", false),
+            ],
+            ..GalleryExtension::default()
+        };
+        let select = |line: &str| {
+            let ext = try_parse_ext_line(line).unwrap();
+            ext.select_version(&ext_data).map(|x| x.version.clone())
+        };
+        assert_eq!(select("p.n@^1").unwrap(), "1.2.0");
+        // the semver pre-releases only match a range naming one
+        assert_eq!(select("p.n@>=1.3.0-beta.0").unwrap(), "1.3.0-beta.1");
+        assert!(matches!(
+            select("p.n@>=1.3.0"),
+            Err(HelmError::VersionNotFound(_))
+        ));
+        // the exact versions and no version are not filtered
+        assert_eq!(select("p.n@1.3.0").unwrap(), "1.3.0");
+        assert_eq!(select("p.n").unwrap(), "1.3.0");
+    }
+
+    fn emit(lines: &[&str], format: &str, download_dir: &str) -> String {
+        let extensions: Vec<Extension> = lines
+            .iter()
+            .map(|x| try_parse_ext_line(x).unwrap())
+            .collect();
+        emit_extensions(&extensions, format, download_dir).unwrap()
+    }
+
+    #[test]
+    fn test_emit_install() {
+        let lines = ["p.b@1.0.0", "p.a@2.0.0=linux-x64"];
+        let text = emit(&lines, "install", "my vsix");
+        let sep = MAIN_SEPARATOR;
+        assert_eq!(
+            text,
+            format!(
+                "code --install-extension 'my vsix{sep}p.b@1.0.0.vsix'\n\
+                 code --install-extension 'my vsix{sep}p.a@2.0.0=linux-x64.vsix'\n"
+            )
+        );
+        let text = emit(&["p.a@1.0.0"], "install", "it's");
+        assert_eq!(
+            text,
+            format!("code --install-extension 'it'\\''s{sep}p.a@1.0.0.vsix'\n")
+        );
+    }
+
+    #[test]
+    fn test_emit_recommendations_dedup() {
+        let lines = [
+            "p.b@1.0.0=linux-x64",
+            "p.a@1.0.0",
+            "p.b@1.0.0=darwin-arm64",
+            "p.a@2.0.0",
+        ];
+        let data: json_value::Value =
+            serde_json::from_str(&emit(&lines, "recommendations", "d")).unwrap();
+        assert_eq!(data, json!({ "recommendations": ["p.a", "p.b"] }));
+    }
+
+    #[test]
+    fn test_emit_json_formats() {
+        let lines = ["p.a@1.0.0=linux-x64", "p.b"];
+        let data: json_value::Value =
+            serde_json::from_str(&emit(&lines, "extensions-json", "d")).unwrap();
+        assert_eq!(
+            data,
+            json!([
+                {
+                    "identifier": {"id": "p.a"},
+                    "version": "1.0.0",
+                    "metadata": {"targetPlatform": "linux-x64"},
+                },
+                {"identifier": {"id": "p.b"}, "version": null},
+            ])
+        );
+        let data: json_value::Value =
+            serde_json::from_str(&emit(&lines, "devcontainer", "d")).unwrap();
+        assert_eq!(
+            data,
+            json!({ "customizations": { "vscode": { "extensions": ["p.a@1.0.0", "p.b"] } } })
+        );
+        let lines = ["p.b@1.0.0=linux-x64", "p.a", "p.b@1.0.0=darwin-arm64"];
+        let data: json_value::Value =
+            serde_json::from_str(&emit(&lines, "devcontainer", "d")).unwrap();
+        assert_eq!(
+            data,
+            json!({ "customizations": { "vscode": { "extensions": ["p.a", "p.b@1.0.0"] } } })
+        );
+        assert!(matches!(
+            emit_extensions(&[], "yaml", "d"),
+            Err(HelmError::InvalidOption { .. })
+        ));
+    }
 }
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+
+pub static PROPERTY_ENGINE: &str = "Microsoft.VisualStudio.Code.Engine";
+pub static PROPERTY_PRE_RELEASE: &str = "Microsoft.VisualStudio.Code.PreRelease";
+pub static ASSET_VSIX_PACKAGE: &str = "Microsoft.VisualStudio.Services.VSIXPackage";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GalleryResponse {
+    pub results: Vec<GalleryResult>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryResult {
+    pub extensions: Vec<GalleryExtension>,
+    pub result_metadata: Vec<GalleryResultMetadata>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryResultMetadata {
+    pub metadata_type: String,
+    pub metadata_items: Vec<GalleryMetadataItem>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GalleryMetadataItem {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryExtension {
+    pub extension_id: String,
+    pub extension_name: String,
+    pub display_name: String,
+    pub short_description: Option<String>,
+    pub publisher: GalleryPublisher,
+    pub versions: Vec<GalleryVersion>,
+    pub categories: Vec<String>,
+    pub tags: Vec<String>,
+    pub statistics: Vec<GalleryStatistic>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryPublisher {
+    pub publisher_id: String,
+    pub publisher_name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryVersion {
+    pub version: String,
+    pub target_platform: Option<String>,
+    pub last_updated: String,
+    pub asset_uri: Option<String>,
+    pub fallback_asset_uri: Option<String>,
+    pub files: Vec<GalleryFile>,
+    pub properties: Vec<GalleryProperty>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryFile {
+    pub asset_type: String,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GalleryProperty {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GalleryStatistic {
+    pub statistic_name: String,
+    pub value: f64,
+}
+
+impl GalleryResult {
+    pub fn get_result_count(&self) -> Option<usize> {
+        self.result_metadata
+            .iter()
+            .find(|x| x.metadata_type == "ResultCount")
+            .and_then(|x| x.metadata_items.first())
+            .map(|x| x.count)
+    }
+}
+
+impl GalleryExtension {
+    pub fn get_extension_id(&self) -> String {
+        format!("{}.{}", self.publisher.publisher_name, self.extension_name)
+    }
+
+    pub fn get_statistic(&self, name: &str) -> Option<f64> {
+        self.statistics
+            .iter()
+            .find(|x| x.statistic_name == name)
+            .map(|x| x.value)
+    }
+}
+
+impl GalleryVersion {
+    pub fn get_property(&self, key: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|x| x.key == key)
+            .map(|x| x.value.as_str())
+    }
+
+    pub fn get_engine(&self) -> Option<&str> {
+        self.get_property(PROPERTY_ENGINE)
+    }
+
+    pub fn is_pre_release(&self) -> bool {
+        self.get_property(PROPERTY_PRE_RELEASE) == Some("true")
+    }
+
+    pub fn get_file(&self, asset_type: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|x| x.asset_type == asset_type)
+            .map(|x| x.source.as_str())
+    }
+
+    pub fn get_download_url(&self) -> Option<String> {
+        if let Some(source) = self.get_file(ASSET_VSIX_PACKAGE) {
+            return Some(source.to_string());
+        }
+        self.asset_uri
+            .as_ref()
+            .or(self.fallback_asset_uri.as_ref())
+            .map(|x| format!("{}/{}", x, ASSET_VSIX_PACKAGE))
+    }
+}
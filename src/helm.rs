@@ -0,0 +1,452 @@
+use log::LevelFilter;
+use reqwest::blocking::Client;
+use std::sync::Arc;
+
+use crate::cache::Cache;
+use crate::config::{self, ProxyConfig, TlsConfig};
+use crate::error::HelmError;
+#[cfg(feature = "async")]
+use crate::nonblocking::{AsyncHelm, AsyncHttpRegistry, AsyncRegistry, BlockingRegistry};
+use crate::progress;
+use crate::registry::{HttpRegistry, Registry};
+use crate::retry::RetryPolicy;
+use crate::utils;
+
+pub static QUERY_URL: &str =
+    "https://marketplace.visualstudio.com/_apis/public/gallery/extensionQuery";
+pub static DOWNLOAD_URL: &str = "https://marketplace.visualstudio.com/_apis/public/gallery/publishers/{publisher}/vsextensions/{package}/{version}/vspackage";
+pub static UPDATE_URL: &str = "https://update.code.visualstudio.com";
+pub static DEFAULT_CONCURRENCY: usize = 1;
+
+// the settings shared by the marketplace and vscode server requests,
+// Helm::from_config() follows the loaded config, Helm::builder() starts from the defaults
+#[derive(Debug, Clone)]
+pub struct Helm {
+    registry: Arc<dyn Registry>,
+    cache: Option<Cache>,
+    concurrency: usize,
+    progress: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HelmBuilder {
+    query_url: Option<String>,
+    download_url: Option<String>,
+    update_url: Option<String>,
+    proxy: ProxyConfig,
+    tls: TlsConfig,
+    client: Option<Client>,
+    download_client: Option<Client>,
+    registry: Option<Arc<dyn Registry>>,
+    #[cfg(feature = "async")]
+    async_client: Option<reqwest::Client>,
+    #[cfg(feature = "async")]
+    async_download_client: Option<reqwest::Client>,
+    #[cfg(feature = "async")]
+    async_registry: Option<Arc<dyn AsyncRegistry>>,
+    cache_dir: Option<String>,
+    retry: Option<RetryPolicy>,
+    concurrency: Option<usize>,
+    progress: Option<bool>,
+    log_level: Option<LevelFilter>,
+}
+
+impl HelmBuilder {
+    pub fn new() -> HelmBuilder {
+        HelmBuilder::default()
+    }
+
+    pub fn from_config() -> HelmBuilder {
+        let config = config::get_config();
+        HelmBuilder {
+            query_url: config.registry.query_url.clone(),
+            download_url: config.registry.download_url.clone(),
+            update_url: config.registry.update_url.clone(),
+            proxy: config.proxy.clone(),
+            tls: config.tls.clone(),
+            cache_dir: config.cache.dir.clone(),
+            retry: Some(RetryPolicy::from_config()),
+            concurrency: config.extension.concurrency,
+            ..HelmBuilder::default()
+        }
+    }
+
+    pub fn query_url(mut self, url: &str) -> HelmBuilder {
+        self.query_url = Some(url.to_string());
+        self
+    }
+
+    // without the template, the asset url returned by the query is used
+    pub fn download_url(mut self, url: &str) -> HelmBuilder {
+        self.download_url = Some(url.to_string());
+        self
+    }
+
+    pub fn update_url(mut self, url: &str) -> HelmBuilder {
+        self.update_url = Some(url.to_string());
+        self
+    }
+
+    // the proxy and tls options are unused for the clients set by http_client and download_client
+    pub fn proxy(mut self, url: &str) -> HelmBuilder {
+        self.proxy.url = Some(url.to_string());
+        self
+    }
+
+    // the comma separated hosts, applied to the proxy from the environment too
+    pub fn no_proxy(mut self, no_proxy: &str) -> HelmBuilder {
+        self.proxy.no_proxy = Some(no_proxy.to_string());
+        self
+    }
+
+    pub fn ca_bundle(mut self, file: &str) -> HelmBuilder {
+        self.tls.ca_bundle = Some(file.to_string());
+        self
+    }
+
+    // the key is read from the certificate file without key_file
+    pub fn client_cert(mut self, cert_file: &str, key_file: Option<&str>) -> HelmBuilder {
+        self.tls.client_cert = Some(cert_file.to_string());
+        self.tls.client_key = key_file.map(str::to_string);
+        self
+    }
+
+    // the client of the queries
+    pub fn http_client(mut self, client: Client) -> HelmBuilder {
+        self.client = Some(client);
+        self
+    }
+
+    // the client of the downloads, which may take longer than a request timeout,
+    // one without the total timeout is built from the proxy and tls options by default
+    pub fn download_client(mut self, client: Client) -> HelmBuilder {
+        self.download_client = Some(client);
+        self
+    }
+
+    // replace the marketplace and update server, the url and client settings are unused then,
+    // build_async() runs it on the blocking threads unless an async_registry is set
+    pub fn registry<R: Registry + 'static>(mut self, registry: R) -> HelmBuilder {
+        self.registry = Some(Arc::new(registry));
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_http_client(mut self, client: reqwest::Client) -> HelmBuilder {
+        self.async_client = Some(client);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_download_client(mut self, client: reqwest::Client) -> HelmBuilder {
+        self.async_download_client = Some(client);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_registry<R: AsyncRegistry + 'static>(mut self, registry: R) -> HelmBuilder {
+        self.async_registry = Some(Arc::new(registry));
+        self
+    }
+
+    pub fn cache_dir(mut self, dir: &str) -> HelmBuilder {
+        self.cache_dir = Some(dir.to_string());
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> HelmBuilder {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> HelmBuilder {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    // the progress bars are shown only on a terminal by default,
+    // otherwise the progress goes to the log messages
+    pub fn progress(mut self, progress: bool) -> HelmBuilder {
+        self.progress = Some(progress);
+        self
+    }
+
+    // install a logger for the messages at build, RUST_LOG overrides the level,
+    // skip it to keep the logger of the application
+    pub fn log_level(mut self, level: LevelFilter) -> HelmBuilder {
+        self.log_level = Some(level);
+        self
+    }
+
+    pub fn build(self) -> Result<Helm, HelmError> {
+        self.init_logger();
+        let registry = match &self.registry {
+            Some(v) => v.clone(),
+            None => Arc::new(self.build_http_registry()?),
+        };
+        Ok(Helm {
+            registry,
+            cache: self.get_cache(),
+            concurrency: self.get_concurrency(),
+            progress: self.get_progress(),
+        })
+    }
+
+    pub fn build_http_registry(&self) -> Result<HttpRegistry, HelmError> {
+        let client = match &self.client {
+            Some(v) => v.clone(),
+            None => utils::get_http_client(&self.proxy, &self.tls)?,
+        };
+        let download_client = match &self.download_client {
+            Some(v) => v.clone(),
+            None => utils::get_download_client(&self.proxy, &self.tls)?,
+        };
+        Ok(HttpRegistry {
+            query_url: self.get_query_url(),
+            download_url: self.download_url.clone(),
+            update_url: self.get_update_url(),
+            client,
+            download_client,
+            retry: self.retry.clone().unwrap_or_default(),
+            progress: self.get_progress(),
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<AsyncHelm, HelmError> {
+        self.init_logger();
+        let registry: Arc<dyn AsyncRegistry> = match (&self.async_registry, &self.registry) {
+            (Some(v), _) => v.clone(),
+            (None, Some(v)) => Arc::new(BlockingRegistry {
+                registry: v.clone(),
+            }),
+            (None, None) => Arc::new(self.build_async_http_registry()?),
+        };
+        Ok(AsyncHelm {
+            registry,
+            cache: self.get_cache(),
+            concurrency: self.get_concurrency(),
+            progress: self.get_progress(),
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub fn build_async_http_registry(&self) -> Result<AsyncHttpRegistry, HelmError> {
+        let client = match &self.async_client {
+            Some(v) => v.clone(),
+            None => utils::get_async_http_client(&self.proxy, &self.tls)?,
+        };
+        let download_client = match &self.async_download_client {
+            Some(v) => v.clone(),
+            None => utils::get_async_download_client(&self.proxy, &self.tls)?,
+        };
+        Ok(AsyncHttpRegistry {
+            query_url: self.get_query_url(),
+            download_url: self.download_url.clone(),
+            update_url: self.get_update_url(),
+            client,
+            download_client,
+            retry: self.retry.clone().unwrap_or_default(),
+            progress: self.get_progress(),
+        })
+    }
+
+    fn init_logger(&self) {
+        if let Some(level) = self.log_level {
+            progress::init_logger(level);
+        }
+    }
+
+    fn get_query_url(&self) -> String {
+        self.query_url.as_deref().unwrap_or(QUERY_URL).to_string()
+    }
+
+    fn get_update_url(&self) -> String {
+        let update_url = self.update_url.as_deref().unwrap_or(UPDATE_URL);
+        update_url.trim_end_matches("/").to_string()
+    }
+
+    fn get_cache(&self) -> Option<Cache> {
+        self.cache_dir.as_deref().map(Cache::new)
+    }
+
+    fn get_concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+    }
+
+    fn get_progress(&self) -> bool {
+        self.progress.unwrap_or_else(progress::is_enabled)
+    }
+}
+
+impl Helm {
+    pub fn builder() -> HelmBuilder {
+        HelmBuilder::new()
+    }
+
+    pub fn from_config() -> Result<Helm, HelmError> {
+        HelmBuilder::from_config().build()
+    }
+
+    pub fn get_registry(&self) -> &dyn Registry {
+        self.registry.as_ref()
+    }
+
+    pub fn get_cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub fn get_concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub fn get_progress(&self) -> bool {
+        self.progress
+    }
+}
+
+// the test registry with the extension lines, each vsix holds its version
+#[cfg(test)]
+pub(crate) fn make_test_registry(lines: &[&str]) -> crate::registry::MemoryRegistry {
+    let mut registry = crate::registry::MemoryRegistry::new();
+    for line in lines {
+        let ext = crate::extension::parse_ext_line(line).unwrap();
+        let data = ext.get_version().unwrap_or_default().as_bytes().to_vec();
+        registry.add_extension(ext, data);
+    }
+    registry
+}
+
+#[cfg(test)]
+pub(crate) fn make_test_helm(lines: &[&str]) -> Helm {
+    Helm::builder()
+        .registry(make_test_registry(lines))
+        .progress(false)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension;
+    use crate::registry::MemoryRegistry;
+    use std::fs;
+    use tempfile::TempDir;
+
+    static COMMIT: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    static EXTENSIONS: [&str; 2] = ["pub.pkg@1.0.0", "pub.pkg@1.2.0"];
+
+    fn make_registry() -> MemoryRegistry {
+        let mut registry = make_test_registry(&EXTENSIONS);
+        registry.add_release("stable", "linux", "x64", COMMIT, ".tar.gz", b"tgz".to_vec());
+        registry
+    }
+
+    #[test]
+    fn test_resolve_and_download_from_registry() {
+        let helm = make_test_helm(&EXTENSIONS);
+        let ext = extension::parse_ext_line("pub.pkg").unwrap();
+        let ext = helm.resolve(&ext).unwrap();
+        assert_eq!(ext.get_version(), Some("1.2.0"));
+
+        let dir = TempDir::new().unwrap();
+        let download_dir = dir.path().to_string_lossy().to_string();
+        assert!(helm.download_extension(&ext, &download_dir, None).unwrap());
+        let data = fs::read(dir.path().join("pub.pkg@1.2.0.vsix")).unwrap();
+        assert_eq!(data, b"1.2.0");
+        // the existing file is kept
+        assert!(!helm.download_extension(&ext, &download_dir, None).unwrap());
+    }
+
+    #[test]
+    fn test_download_bypasses_cache() {
+        let cache_dir = TempDir::new().unwrap();
+        let helm = Helm::builder()
+            .registry(make_registry())
+            .cache_dir(&cache_dir.path().to_string_lossy())
+            .progress(false)
+            .build()
+            .unwrap();
+        let ext = extension::parse_ext_line("pub.pkg").unwrap();
+        let ext = helm.resolve(&ext).unwrap();
+        let dir = TempDir::new().unwrap();
+        let download_dir = dir.path().to_string_lossy().to_string();
+        assert!(helm.download_extension(&ext, &download_dir, None).unwrap());
+        // fetched from the cache unless the download is forced
+        let vsix_file = dir.path().join("pub.pkg@1.2.0.vsix");
+        fs::remove_file(&vsix_file).unwrap();
+        assert!(!helm.download_extension(&ext, &download_dir, None).unwrap());
+        fs::remove_file(&vsix_file).unwrap();
+        let downloaded = helm.download_extension(&ext, &download_dir, Some(false));
+        assert!(downloaded.unwrap());
+        assert_eq!(fs::read(&vsix_file).unwrap(), b"1.2.0");
+    }
+
+    #[test]
+    fn test_builder_client_options() {
+        let result = Helm::builder().ca_bundle("/nonexistent/ca.pem").build();
+        match result {
+            Err(HelmError::Config { message, .. }) => {
+                assert_eq!(message, "read /nonexistent/ca.pem failed")
+            }
+            v => panic!("expect a config error, got {:?}", v.map(|_| ())),
+        }
+        let result = Helm::builder().proxy("http://[::1").build();
+        assert!(matches!(result, Err(HelmError::Config { .. })));
+        // the clients are built by the caller
+        let helm = Helm::builder()
+            .proxy("http://[::1")
+            .http_client(Client::new())
+            .download_client(Client::new())
+            .build();
+        assert!(helm.is_ok());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_resolve_and_download_from_registry() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // the blocking registry runs on the blocking threads, the async one directly
+        let builders = [
+            Helm::builder().registry(make_registry()),
+            Helm::builder().async_registry(make_registry()),
+        ];
+        for builder in builders {
+            let (dir, cache_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+            let helm = builder
+                .cache_dir(&cache_dir.path().to_string_lossy())
+                .progress(false)
+                .build_async()
+                .unwrap();
+            let download_dir = dir.path().to_string_lossy().to_string();
+            runtime.block_on(async {
+                let ext = extension::parse_ext_line("pub.pkg@^1.0").unwrap();
+                let ext = helm.resolve(&ext).await.unwrap();
+                assert_eq!(ext.get_version(), Some("1.2.0"));
+                let downloaded = helm.download_extension(&ext, &download_dir, None).await;
+                assert!(downloaded.unwrap());
+                let vsix_file = dir.path().join("pub.pkg@1.2.0.vsix");
+                assert_eq!(fs::read(&vsix_file).unwrap(), b"1.2.0");
+                // fetched from the cache the second time
+                fs::remove_file(&vsix_file).unwrap();
+                let downloaded = helm.download_extension(&ext, &download_dir, None).await;
+                assert!(!downloaded.unwrap());
+                assert_eq!(fs::read(&vsix_file).unwrap(), b"1.2.0");
+
+                let commit = helm.get_latest_release("stable", "linux", "x64").await;
+                assert_eq!(commit.unwrap(), COMMIT);
+                let archive_file = helm
+                    .download_release_file("stable", COMMIT, "server-linux", "x64", &download_dir)
+                    .await
+                    .unwrap();
+                assert!(archive_file.ends_with(".tar.gz"));
+                assert_eq!(fs::read(&archive_file).unwrap(), b"tgz");
+            });
+        }
+    }
+}
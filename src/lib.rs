@@ -1,4 +1,16 @@
+pub mod cache;
 pub mod cli;
+pub mod config;
+pub mod error;
 pub mod extension;
+pub mod gallery;
+pub mod helm;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod progress;
+pub mod registry;
+pub mod retry;
 pub mod server;
-pub mod utils;
\ No newline at end of file
+pub mod sync;
+pub mod utils;
+pub mod vsix;
@@ -1,98 +1,491 @@
-use cli::{ExtensionArgs, PortalSubcommand, ServerArgs};
-use env_logger;
-use log::{self, debug, error, info, warn};
-use std::{env, vec};
-
-mod cli;
-mod extension;
-mod server;
-mod utils;
+use log::{debug, error, info, warn, LevelFilter};
+use serde_json::json;
+use std::path::Path;
+use std::{fs, process, vec};
+use vscode_extension_helm::cli::{
+    self, ExtensionArgs, InfoArgs, OutdatedArgs, PortalSubcommand, PruneArgs, SearchArgs,
+    ServerArgs, ServerPruneArgs, SyncArgs, VerifyArgs,
+};
+use vscode_extension_helm::error::HelmError;
+use vscode_extension_helm::{config, extension, progress, server, sync, utils};
 
 fn main() {
     let args = cli::load_args();
-    let _ = env::var("RUST_LOG").map_err(|_| {
-        let log_level = match args.verbose {
-            true => "debug",
-            false => "info",
-        };
-        env::set_var("RUST_LOG", log_level);
+    progress::init_logger(match args.verbose {
+        true => LevelFilter::Debug,
+        false => LevelFilter::Info,
     });
-    env_logger::init();
     debug!("args: {:#?}", &args);
+    config::set_config(args.config.clone());
     match &args.command {
         PortalSubcommand::Extension(v) => {
-            download_extensions(&v);
+            download_extensions(v);
         }
         PortalSubcommand::Server(v) => {
-            download_server(&v);
+            download_server(v);
+        }
+        PortalSubcommand::Search(v) => {
+            search_extensions(v);
+        }
+        PortalSubcommand::Info(v) => {
+            show_extension_info(v);
+        }
+        PortalSubcommand::Outdated(v) => {
+            check_outdated(v);
+        }
+        PortalSubcommand::Prune(v) => {
+            prune_extensions(v);
+        }
+        PortalSubcommand::Verify(v) => {
+            verify_extensions(v);
+        }
+        PortalSubcommand::ServerPrune(v) => {
+            prune_server(v);
+        }
+        PortalSubcommand::Sync(v) => {
+            sync_manifest(v);
         }
     }
-    return;
 }
 
-fn download_extensions(args: &ExtensionArgs) {
-    let extensions = extension::list_extensions(&args.extensions);
-    let mut failed: Vec<String> = vec![];
-    for extension in &extensions {
-        let result = extension.download(&args.download_dir, args.cached);
-        let success = match result {
-            Ok(_) => true,
-            Err(e) => {
-                error!("caught error: {:#?}", e);
-                false
-            }
-        };
-        let ext_name = extension.get_extension_name();
-        if !success {
-            warn!("download extension {} failed", &ext_name);
-            failed.push(ext_name);
+fn list_extensions(extensions: &Vec<String>, strict: bool) -> Vec<extension::Extension> {
+    let (extensions, diagnostics) = match extension::list_extensions(extensions) {
+        Ok(v) => v,
+        Err(e) => {
+            // an unreadable list or invalid json, nothing of it can be used
+            error!("caught error: {:#?}", e);
+            process::exit(1);
         }
+    };
+    for diagnostic in &diagnostics {
+        warn!("skip {}", diagnostic);
     }
-    if failed.len() > 0 {
+    if strict && !diagnostics.is_empty() {
+        error!(
+            "{} invalid entries in the extension list",
+            diagnostics.len()
+        );
+        process::exit(1);
+    }
+    extensions
+}
+
+fn download_extensions(args: &ExtensionArgs) {
+    let extensions = list_extensions(&args.extensions, args.strict);
+    let (extensions, mut failed) = extension::resolve_extensions(&extensions);
+    failed.extend(extension::download_extensions(
+        &extensions,
+        &args.download_dir,
+        args.cached,
+        args.concurrency,
+    ));
+    if !failed.is_empty() {
         error!("download some failed:\n{}", failed.join(" "));
     } else {
         info!("download all succeed");
     }
-    return;
+    if let Some(format) = &args.emit {
+        let downloaded: Vec<extension::Extension> = extensions
+            .into_iter()
+            .filter(|x| !failed.contains(&x.get_extension_name()))
+            .collect();
+        if let Err(e) = emit_extensions(&downloaded, format, args) {
+            error!("caught error: {:#?}", e);
+        }
+    }
+}
+
+fn emit_extensions(
+    extensions: &[extension::Extension],
+    format: &str,
+    args: &ExtensionArgs,
+) -> Result<(), HelmError> {
+    let data = extension::emit_extensions(extensions, format, &args.download_dir)?;
+    match &args.emit_file {
+        Some(emit_file) => {
+            let emit_file = Path::new(emit_file);
+            if let Some(parent) = emit_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            utils::write_file_atomic(emit_file, data.as_bytes())?;
+            info!("write {} to {}", format, emit_file.display());
+        }
+        None => print!("{}", data),
+    }
+    Ok(())
 }
 
 fn download_server(args: &ServerArgs) {
     let (platform, arch) = server::get_platform_info(&args.platform, &args.arch);
-    let mut commit = String::from("");
-    let mut prefix = String::from("");
-    let mut archive_file = String::from("");
     let output_dir = args.output_dir.as_ref().map_or(".".into(), |x| x.clone());
-    let res = args
-        .commit
-        .as_ref()
-        .map_or_else(
-            || server::get_latest_release(&platform, &arch),
-            |x| Ok(x.into()),
-        )
-        .and_then(|v| {
-            prefix = match platform.as_str() {
-                "alpine" => format!("cli-{}", &platform),
-                _ => format!("server-{}", &platform),
+    let res = server::download_server(
+        &args.quality,
+        &platform,
+        &arch,
+        args.commit.as_ref(),
+        &output_dir,
+    );
+    match res {
+        Ok(_) => (),
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+        }
+    }
+}
+
+fn search_extensions(args: &SearchArgs) {
+    let result = extension::search_extension(&args.text, &args.sort_by, args.page, args.page_size);
+    let (extensions, total) = match result {
+        Ok(v) => v,
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            return;
+        }
+    };
+    let items: Vec<serde_json::Value> = extensions
+        .iter()
+        .map(|x| {
+            json!({
+                "id": x.get_extension_id(),
+                "version": x.versions.first().map(|v| &v.version),
+                "publisher": x.publisher.display_name,
+                "installs": x.get_statistic("install").map(|v| v as u64),
+                "description": x.short_description.as_deref().unwrap_or_default(),
+            })
+        })
+        .collect();
+    if args.format == "json" {
+        let data = json!({"total": total, "page": args.page, "extensions": items});
+        println!("{}", serde_json::to_string_pretty(&data).unwrap());
+        return;
+    }
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|x| {
+            let get_str = |key: &str| match x.get(key) {
+                Some(serde_json::Value::String(v)) => v.clone(),
+                Some(serde_json::Value::Null) | None => String::from("-"),
+                Some(v) => v.to_string(),
             };
-            commit = v;
-            Ok(())
+            vec![
+                get_str("id"),
+                get_str("version"),
+                get_str("publisher"),
+                get_str("installs"),
+                utils::truncate_text(&get_str("description"), 60),
+            ]
         })
-        .and_then(|_| {
-            let result = server::download_release_file(&commit, &prefix, &arch, &output_dir);
-            match result {
-                Err(e) => Err(e),
-                Ok(file_name) => {
-                    archive_file = file_name;
-                    Ok(())
-                }
-            }
+        .collect();
+    let headers = ["ID", "VERSION", "PUBLISHER", "INSTALLS", "DESCRIPTION"];
+    println!("{}", utils::format_table(&headers, &rows));
+    let shown = args.page.saturating_sub(1) * args.page_size + items.len();
+    info!("showing {} of {} results", shown, total);
+}
+
+fn show_extension_info(args: &InfoArgs) {
+    let (publisher, package) = match args.id.split_once(".") {
+        Some(v) => v,
+        None => {
+            error!("invalid extension id {}", &args.id);
+            return;
+        }
+    };
+    let data = match extension::query_extension(publisher, package, Some(0x1D5)) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            return;
+        }
+    };
+    let get_str = |value: &serde_json::Value, key: &str| {
+        value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let versions: Vec<serde_json::Value> = data
+        .versions
+        .iter()
+        .map(|x| {
+            json!({
+                "version": x.version,
+                "targetPlatform": x.target_platform,
+                "engine": x.get_engine(),
+                "preRelease": x.is_pre_release(),
+                "lastUpdated": x.last_updated,
+            })
         })
-        .and_then(|_| server::prepare_release_dir(&commit, &archive_file, &output_dir));
-    match res {
-        Ok(_) => (),
+        .collect();
+    let statistics: serde_json::Map<String, serde_json::Value> = data
+        .statistics
+        .iter()
+        .map(|x| (x.statistic_name.clone(), json!(x.value)))
+        .collect();
+    let info = json!({
+        "id": extension::get_extension_name(publisher, package, None, None),
+        "displayName": data.display_name,
+        "publisher": data.publisher.display_name,
+        "description": data.short_description.as_deref().unwrap_or_default(),
+        "categories": data.categories,
+        "statistics": statistics,
+        "versions": versions,
+    });
+    if args.format == "json" {
+        println!("{}", serde_json::to_string_pretty(&info).unwrap());
+        return;
+    }
+    let categories: Vec<&str> = info["categories"]
+        .as_array()
+        .map_or(vec![], |x| x.iter().filter_map(|v| v.as_str()).collect());
+    println!("ID:          {}", get_str(&info, "id"));
+    println!("Name:        {}", get_str(&info, "displayName"));
+    println!("Publisher:   {}", get_str(&info, "publisher"));
+    println!("Description: {}", get_str(&info, "description"));
+    println!("Categories:  {}", categories.join(", "));
+    println!("Statistics:");
+    for (key, value) in &statistics {
+        println!("  {}: {}", key, value);
+    }
+    println!("Versions:");
+    let rows: Vec<Vec<String>> = versions
+        .iter()
+        .map(|x| {
+            vec![
+                get_str(x, "version"),
+                x["targetPlatform"].as_str().unwrap_or("-").to_string(),
+                x["engine"].as_str().unwrap_or("-").to_string(),
+                x["preRelease"].to_string(),
+                get_str(x, "lastUpdated"),
+            ]
+        })
+        .collect();
+    let headers = ["VERSION", "PLATFORM", "ENGINE", "PRE-RELEASE", "PUBLISHED"];
+    println!("{}", utils::format_table(&headers, &rows));
+}
+
+fn check_outdated(args: &OutdatedArgs) {
+    let extensions = list_extensions(&args.extensions, args.strict);
+    let extensions = match extension::pin_downloaded_versions(&extensions, &args.download_dir) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            return;
+        }
+    };
+    let ext_ids: Vec<String> = extensions.iter().map(|x| x.get_extension_id()).collect();
+    let all_data = match extension::query_extensions(&ext_ids, None) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            return;
+        }
+    };
+    let mut reports: Vec<extension::Outdated> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for extension in &extensions {
+        match all_data.get(&extension.get_extension_id().to_lowercase()) {
+            Some(v) => reports.push(extension.check_outdated(v, args.vscode_version.as_deref())),
+            None => {
+                warn!(
+                    "extension {} not found in marketplace",
+                    extension.get_extension_id()
+                );
+                failed.push(extension.get_extension_name());
+            }
+        }
+    }
+    let reports: Vec<&extension::Outdated> = reports
+        .iter()
+        .filter(|x| args.all || x.has_update())
+        .collect();
+    if args.format == "json" {
+        let data: Vec<serde_json::Value> = reports
+            .iter()
+            .map(|x| {
+                json!({
+                    "id": x.id,
+                    "current": x.current,
+                    "currentEngine": x.current_engine,
+                    "latest": x.latest,
+                    "latestEngine": x.latest_engine,
+                    "hasUpdate": x.has_update(),
+                    "engineChanged": x.engine_changed(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&data).unwrap());
+    } else {
+        let rows: Vec<Vec<String>> = reports
+            .iter()
+            .map(|x| {
+                let engine = match x.engine_changed() {
+                    true => format!(
+                        "{} -> {}",
+                        x.current_engine.as_deref().unwrap_or("-"),
+                        x.latest_engine.as_deref().unwrap_or("-")
+                    ),
+                    false => String::from("unchanged"),
+                };
+                vec![
+                    x.id.clone(),
+                    x.current.clone().unwrap_or(String::from("-")),
+                    x.latest.clone().unwrap_or(String::from("-")),
+                    engine,
+                ]
+            })
+            .collect();
+        let headers = ["ID", "CURRENT", "LATEST", "ENGINE"];
+        println!("{}", utils::format_table(&headers, &rows));
+    }
+    if !failed.is_empty() {
+        error!("check some failed:\n{}", failed.join(" "));
+    }
+}
+
+fn prune_extensions(args: &PruneArgs) {
+    let referenced = args
+        .extensions
+        .as_ref()
+        .map(|x| list_extensions(x, args.strict));
+    let result = extension::prune_extensions(
+        &args.download_dir,
+        args.keep,
+        referenced.as_ref(),
+        args.dry_run,
+    );
+    match result {
+        Ok((removed, reclaimed)) => {
+            let action = match args.dry_run {
+                true => "would remove",
+                false => "removed",
+            };
+            info!(
+                "{} {} files, {} reclaimed",
+                action,
+                removed.len(),
+                utils::format_size(reclaimed)
+            );
+        }
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+        }
+    }
+}
+
+fn verify_extensions(args: &VerifyArgs) {
+    let corrupted = match extension::verify_extensions(&args.download_dir) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+            return;
+        }
+    };
+    if args.format == "json" {
+        let data: Vec<serde_json::Value> = corrupted
+            .iter()
+            .map(|(path, reason)| json!({"file": path, "error": reason}))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&data).unwrap());
+    } else if !corrupted.is_empty() {
+        let rows: Vec<Vec<String>> = corrupted
+            .iter()
+            .map(|(path, reason)| vec![path.display().to_string(), reason.clone()])
+            .collect();
+        println!("{}", utils::format_table(&["FILE", "ERROR"], &rows));
+    }
+    if !args.redownload || corrupted.is_empty() {
+        return;
+    }
+    let mut extensions: Vec<extension::Extension> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for (path, _) in &corrupted {
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        match extension::parse_ext_line(&file_name) {
+            Some(v) if v.get_version().is_some() => extensions.push(v),
+            _ => {
+                warn!("can not tell the extension version of {}", &file_name);
+                failed.push(file_name);
+            }
+        }
+    }
+    failed.extend(extension::download_extensions(
+        &extensions,
+        &args.download_dir,
+        Some(false),
+        1,
+    ));
+    if !failed.is_empty() {
+        error!("download some failed:\n{}", failed.join(" "));
+    } else {
+        info!("download all corrupted files again");
+    }
+}
+
+fn prune_server(args: &ServerPruneArgs) {
+    let releases = match &args.commit {
+        Some(_) => vec![],
+        None => {
+            let (platform, arch) = server::get_platform_info(&args.platform, &args.arch);
+            server::list_releases(&args.quality, &platform, &arch).unwrap_or_else(|e| {
+                warn!(
+                    "query vscode server releases failed, order by modified time: {}",
+                    e
+                );
+                vec![]
+            })
+        }
+    };
+    let result = server::prune_release_dirs(
+        &args.output_dir,
+        args.keep,
+        args.commit.as_ref(),
+        &releases,
+        args.dry_run,
+    );
+    match result {
+        Ok((removed, reclaimed)) => {
+            let action = match args.dry_run {
+                true => "would remove",
+                false => "removed",
+            };
+            info!(
+                "{} {} paths, {} reclaimed",
+                action,
+                removed.len(),
+                utils::format_size(reclaimed)
+            );
+        }
+        Err(e) => {
+            error!("caught error: {:#?}", e);
+        }
+    }
+}
+
+fn sync_manifest(args: &SyncArgs) {
+    let result = sync::load_manifest(&args.manifest).and_then(|manifest| {
+        let output_dir = args
+            .output_dir
+            .clone()
+            .or(manifest.output_dir.clone())
+            .unwrap_or(".".into());
+        sync::sync_manifest(
+            &manifest,
+            &output_dir,
+            args.cached,
+            args.concurrency,
+            args.prune,
+        )
+    });
+    match result {
+        Ok(failed) if !failed.is_empty() => {
+            error!("sync some failed:\n{}", failed.join(" "));
+        }
+        Ok(_) => {
+            info!("sync all succeed");
+        }
         Err(e) => {
             error!("caught error: {:#?}", e);
-            ()
         }
     }
 }
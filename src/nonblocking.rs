@@ -0,0 +1,478 @@
+use flate2::write::GzDecoder;
+use log::debug;
+use reqwest::header::{HeaderMap, CONTENT_ENCODING, RANGE};
+use reqwest::StatusCode;
+use serde_json::value as json_value;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::cache::Cache;
+use crate::error::HelmError;
+use crate::extension::{self, Extension};
+use crate::gallery::{GalleryExtension, GalleryResponse, GalleryResult};
+use crate::helm::HelmBuilder;
+use crate::progress::FileProgress;
+use crate::registry::{MemoryRegistry, Registry};
+use crate::retry::{RetryError, RetryPolicy};
+use crate::server;
+use crate::utils;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// the async counterpart of Registry, the futures are boxed to keep the trait object safe
+pub trait AsyncRegistry: Debug + Send + Sync {
+    fn query_extension<'a>(
+        &'a self,
+        publisher: &'a str,
+        package: &'a str,
+        flags: Option<usize>,
+    ) -> BoxFuture<'a, Result<GalleryExtension, HelmError>>;
+
+    fn download_extension<'a>(
+        &'a self,
+        extension: &'a Extension,
+        output_file: &'a str,
+        resume: bool,
+    ) -> BoxFuture<'a, Result<(), HelmError>>;
+
+    // the commits are ordered from the latest
+    fn list_releases<'a>(
+        &'a self,
+        quality: &'a str,
+        platform: &'a str,
+        arch: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, HelmError>>;
+
+    // returns the archive file, archive_path with the archive suffix
+    fn download_release_file<'a>(
+        &'a self,
+        quality: &'a str,
+        commit: &'a str,
+        prefix: &'a str,
+        arch: &'a str,
+        archive_path: &'a str,
+    ) -> BoxFuture<'a, Result<String, HelmError>>;
+}
+
+// the async counterpart of Helm, built by HelmBuilder::build_async()
+#[derive(Debug, Clone)]
+pub struct AsyncHelm {
+    pub(crate) registry: Arc<dyn AsyncRegistry>,
+    pub(crate) cache: Option<Cache>,
+    pub(crate) concurrency: usize,
+    pub(crate) progress: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncHttpRegistry {
+    pub(crate) query_url: String,
+    pub(crate) download_url: Option<String>,
+    pub(crate) update_url: String,
+    pub(crate) client: reqwest::Client,
+    pub(crate) download_client: reqwest::Client,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) progress: bool,
+}
+
+// runs a blocking Registry set by HelmBuilder::registry() off the async threads
+#[derive(Debug, Clone)]
+pub(crate) struct BlockingRegistry {
+    pub(crate) registry: Arc<dyn Registry>,
+}
+
+impl AsyncHelm {
+    pub fn builder() -> HelmBuilder {
+        HelmBuilder::new()
+    }
+
+    pub fn from_config() -> Result<AsyncHelm, HelmError> {
+        HelmBuilder::from_config().build_async()
+    }
+
+    pub fn get_registry(&self) -> &dyn AsyncRegistry {
+        self.registry.as_ref()
+    }
+
+    pub fn get_cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub fn get_concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub fn get_progress(&self) -> bool {
+        self.progress
+    }
+}
+
+impl AsyncHttpRegistry {
+    pub fn get_query_url(&self) -> &str {
+        &self.query_url
+    }
+
+    pub fn get_download_url(&self) -> Option<&str> {
+        self.download_url.as_deref()
+    }
+
+    pub fn get_update_url(&self) -> &str {
+        &self.update_url
+    }
+
+    pub fn get_client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub async fn download_file(
+        &self,
+        name: &str,
+        url: &str,
+        body_file: &str,
+        resume: bool,
+    ) -> Result<HeaderMap, HelmError> {
+        self.fetch_file(name, url, body_file, resume, false).await
+    }
+
+    // with decode, a gzip encoded body is decoded while writing, instead of decoding the file
+    // on a blocking thread after downloading
+    async fn fetch_file(
+        &self,
+        name: &str,
+        url: &str,
+        body_file: &str,
+        resume: bool,
+        decode: bool,
+    ) -> Result<HeaderMap, HelmError> {
+        let progress = FileProgress::new(name, self.progress);
+        let result = self
+            .retry
+            .run_async(name, || async {
+                let offset = match resume {
+                    true => fs::metadata(body_file).await.map_or(0, |x| x.len()),
+                    false => 0,
+                };
+                let mut request = self.download_client.get(url);
+                if offset > 0 {
+                    debug!("resume downloading {} from {}", body_file, offset);
+                    request = request.header(RANGE, format!("bytes={}-", offset));
+                }
+                let mut response = request
+                    .send()
+                    .await
+                    .map_err(utils::get_request_retry_error)?;
+                let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+                let gzip = decode
+                    && response
+                        .headers()
+                        .get(CONTENT_ENCODING)
+                        .and_then(|x| x.to_str().ok())
+                        .is_some_and(|x| x.contains("gzip"));
+                // the decoded part on disk can not be continued by a range of the encoded body
+                let unresumable = gzip && partial;
+                if offset > 0
+                    && (response.status() == StatusCode::RANGE_NOT_SATISFIABLE || unresumable)
+                {
+                    fs::remove_file(body_file).await?;
+                    let error = HelmError::InvalidResponse {
+                        url: url.to_string(),
+                        message: format!("unable to resume downloading {}", body_file),
+                    };
+                    return Err(RetryError::transient(error, None));
+                }
+                utils::check_status(response.status(), response.headers(), url)?;
+                let headers = response.headers().clone();
+                let position = if partial { offset } else { 0 };
+                progress.start(response.content_length().map(|x| x + position), position);
+                let mut f_o = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(partial)
+                    .truncate(!partial)
+                    .open(body_file)
+                    .await?;
+                let mut decoder = gzip.then(|| GzDecoder::new(vec![]));
+                let result = async {
+                    let transient = |error: HelmError| {
+                        let error = error.context(format!("download {} failed", url));
+                        RetryError::transient(error, None)
+                    };
+                    let decode_error = |error: io::Error| {
+                        HelmError::from(error).context(format!("decode {} failed", body_file))
+                    };
+                    while let Some(chunk) =
+                        response.chunk().await.map_err(|e| transient(e.into()))?
+                    {
+                        progress.inc(chunk.len() as u64);
+                        let data = match decoder.as_mut() {
+                            Some(decoder) => {
+                                decoder.write_all(&chunk).map_err(decode_error)?;
+                                std::mem::take(decoder.get_mut())
+                            }
+                            None => chunk.to_vec(),
+                        };
+                        f_o.write_all(&data)
+                            .await
+                            .map_err(|e| transient(e.into()))?;
+                    }
+                    if let Some(decoder) = decoder.take() {
+                        let data = decoder.finish().map_err(decode_error)?;
+                        f_o.write_all(&data).await?;
+                    }
+                    Ok::<(), RetryError>(())
+                };
+                if let Err(e) = result.await {
+                    // a partly decoded body is not resumable, download it again
+                    if gzip {
+                        fs::remove_file(body_file).await?;
+                    }
+                    return Err(e);
+                }
+                f_o.flush().await?;
+                // the body is renamed into place by the callers
+                f_o.sync_all().await?;
+                Ok(headers)
+            })
+            .await;
+        progress.finish(result.is_ok());
+        result
+    }
+
+    async fn query_gallery(
+        &self,
+        filters: &json_value::Value,
+        flags: usize,
+    ) -> Result<GalleryResult, HelmError> {
+        let payload = extension::get_gallery_payload(filters, flags);
+        let query_url = self.get_query_url();
+        let data: GalleryResponse = self
+            .retry
+            .run_async("query gallery", || async {
+                let mut request = self.client.post(query_url);
+                for (key, val) in &extension::GALLERY_HEADERS {
+                    request = request.header(*key, *val);
+                }
+                let response = request
+                    .json(&payload)
+                    .send()
+                    .await
+                    .map_err(utils::get_request_retry_error)?;
+                utils::check_status(response.status(), response.headers(), query_url)?;
+                Ok(response.json().await?)
+            })
+            .await?;
+        extension::get_gallery_result(data, query_url)
+    }
+}
+
+impl AsyncRegistry for AsyncHttpRegistry {
+    fn query_extension<'a>(
+        &'a self,
+        publisher: &'a str,
+        package: &'a str,
+        flags: Option<usize>,
+    ) -> BoxFuture<'a, Result<GalleryExtension, HelmError>> {
+        Box::pin(async move {
+            let ext_name = extension::get_extension_name(publisher, package, None, None);
+            let filters = extension::get_extension_filters(&ext_name);
+            let data = self
+                .query_gallery(&filters, flags.unwrap_or(extension::QUERY_FLAGS))
+                .await
+                .map_err(|e| e.context(format!("query extension {} info failed", &ext_name)))?;
+            extension::get_first_extension(data, ext_name)
+        })
+    }
+
+    fn download_extension<'a>(
+        &'a self,
+        extension: &'a Extension,
+        output_file: &'a str,
+        resume: bool,
+    ) -> BoxFuture<'a, Result<(), HelmError>> {
+        Box::pin(async move {
+            let ext_name = extension.get_extension_name();
+            let download_url = extension::get_vsix_url(self.get_download_url(), extension)?;
+            debug!("downloading {}:\nURL: {}", &ext_name, &download_url);
+            let body_file = format!("{}.downloading", output_file);
+            let name = format!("download extension {}", &ext_name);
+            self.fetch_file(&name, &download_url, &body_file, resume, true)
+                .await?;
+            // replace the file by renaming, it may be hard linked from the cache
+            fs::rename(&body_file, output_file).await?;
+            Ok(())
+        })
+    }
+
+    fn list_releases<'a>(
+        &'a self,
+        quality: &'a str,
+        platform: &'a str,
+        arch: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, HelmError>> {
+        Box::pin(async move {
+            let url = server::get_releases_url(self.get_update_url(), quality, platform, arch);
+            let data: json_value::Value = self
+                .retry
+                .run_async("query vscode server", || async {
+                    debug!("query vscode server releases: {}", &url);
+                    let response = self
+                        .client
+                        .get(&url)
+                        .send()
+                        .await
+                        .map_err(utils::get_request_retry_error)?;
+                    utils::check_status(response.status(), response.headers(), &url)?;
+                    Ok(response.json().await?)
+                })
+                .await?;
+            server::parse_commits(&data, url)
+        })
+    }
+
+    fn download_release_file<'a>(
+        &'a self,
+        quality: &'a str,
+        commit: &'a str,
+        prefix: &'a str,
+        arch: &'a str,
+        archive_path: &'a str,
+    ) -> BoxFuture<'a, Result<String, HelmError>> {
+        Box::pin(async move {
+            let url =
+                server::get_release_file_url(self.get_update_url(), quality, commit, prefix, arch);
+            let body_file = format!("{}.downloading", archive_path);
+            let name = format!("download vscode server {}", commit);
+            let headers = self.download_file(&name, &url, &body_file, true).await?;
+            let archive_file = format!("{}{}", archive_path, server::get_archive_ext(&headers));
+            debug!("archive file {}", &archive_file);
+            fs::rename(body_file, &archive_file).await?;
+            Ok(archive_file)
+        })
+    }
+}
+
+impl AsyncRegistry for BlockingRegistry {
+    fn query_extension<'a>(
+        &'a self,
+        publisher: &'a str,
+        package: &'a str,
+        flags: Option<usize>,
+    ) -> BoxFuture<'a, Result<GalleryExtension, HelmError>> {
+        let (registry, publisher, package) = (
+            self.registry.clone(),
+            publisher.to_string(),
+            package.to_string(),
+        );
+        Box::pin(run_blocking(move || {
+            registry.query_extension(&publisher, &package, flags)
+        }))
+    }
+
+    fn download_extension<'a>(
+        &'a self,
+        extension: &'a Extension,
+        output_file: &'a str,
+        resume: bool,
+    ) -> BoxFuture<'a, Result<(), HelmError>> {
+        let (registry, extension, output_file) = (
+            self.registry.clone(),
+            extension.clone(),
+            output_file.to_string(),
+        );
+        Box::pin(run_blocking(move || {
+            registry.download_extension(&extension, &output_file, resume)
+        }))
+    }
+
+    fn list_releases<'a>(
+        &'a self,
+        quality: &'a str,
+        platform: &'a str,
+        arch: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, HelmError>> {
+        let registry = self.registry.clone();
+        let (quality, platform, arch) =
+            (quality.to_string(), platform.to_string(), arch.to_string());
+        Box::pin(run_blocking(move || {
+            registry.list_releases(&quality, &platform, &arch)
+        }))
+    }
+
+    fn download_release_file<'a>(
+        &'a self,
+        quality: &'a str,
+        commit: &'a str,
+        prefix: &'a str,
+        arch: &'a str,
+        archive_path: &'a str,
+    ) -> BoxFuture<'a, Result<String, HelmError>> {
+        let registry = self.registry.clone();
+        let args = [quality, commit, prefix, arch, archive_path].map(str::to_string);
+        Box::pin(run_blocking(move || {
+            let [quality, commit, prefix, arch, archive_path] = &args;
+            registry.download_release_file(quality, commit, prefix, arch, archive_path)
+        }))
+    }
+}
+
+// the added files are small, they are written on the async threads
+impl AsyncRegistry for MemoryRegistry {
+    fn query_extension<'a>(
+        &'a self,
+        publisher: &'a str,
+        package: &'a str,
+        flags: Option<usize>,
+    ) -> BoxFuture<'a, Result<GalleryExtension, HelmError>> {
+        Box::pin(async move { Registry::query_extension(self, publisher, package, flags) })
+    }
+
+    fn download_extension<'a>(
+        &'a self,
+        extension: &'a Extension,
+        output_file: &'a str,
+        resume: bool,
+    ) -> BoxFuture<'a, Result<(), HelmError>> {
+        Box::pin(async move { Registry::download_extension(self, extension, output_file, resume) })
+    }
+
+    fn list_releases<'a>(
+        &'a self,
+        quality: &'a str,
+        platform: &'a str,
+        arch: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<String>, HelmError>> {
+        Box::pin(async move { Registry::list_releases(self, quality, platform, arch) })
+    }
+
+    fn download_release_file<'a>(
+        &'a self,
+        quality: &'a str,
+        commit: &'a str,
+        prefix: &'a str,
+        arch: &'a str,
+        archive_path: &'a str,
+    ) -> BoxFuture<'a, Result<String, HelmError>> {
+        Box::pin(async move {
+            Registry::download_release_file(self, quality, commit, prefix, arch, archive_path)
+        })
+    }
+}
+
+// run the blocking registries and the archive extracting off the async threads
+pub(crate) async fn run_blocking<T, F>(func: F) -> Result<T, HelmError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, HelmError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(func)
+        .await
+        .map_err(|e| HelmError::Io(io::Error::other(e)))?
+}
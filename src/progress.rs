@@ -0,0 +1,143 @@
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{info, LevelFilter};
+use once_cell::sync::Lazy;
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+static MULTI_PROGRESS: Lazy<MultiProgress> = Lazy::new(MultiProgress::new);
+
+pub fn get_multi_progress() -> &'static MultiProgress {
+    &MULTI_PROGRESS
+}
+
+// log through env_logger above the progress bars, RUST_LOG overrides the level,
+// a logger installed already is kept
+pub fn init_logger(level: LevelFilter) {
+    let logger = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .build();
+    let max_level = logger.filter();
+    if LogWrapper::new(MULTI_PROGRESS.clone(), logger)
+        .try_init()
+        .is_ok()
+    {
+        log::set_max_level(max_level);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    io::stderr().is_terminal()
+}
+
+pub struct FileProgress {
+    bar: ProgressBar,
+    name: String,
+    start: Instant,
+    resumed: AtomicU64,
+}
+
+impl FileProgress {
+    pub fn new(name: &str, enabled: bool) -> FileProgress {
+        let bar = match enabled {
+            true => MULTI_PROGRESS.add(ProgressBar::new_spinner()),
+            false => ProgressBar::hidden(),
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{msg} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} {eta}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        bar.set_message(name.to_string());
+        FileProgress {
+            bar,
+            name: name.to_string(),
+            start: Instant::now(),
+            resumed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn start(&self, total: Option<u64>, position: u64) {
+        match total {
+            Some(v) => self.bar.set_length(v),
+            None => self.bar.unset_length(),
+        }
+        self.bar.set_position(position);
+        self.bar.reset_eta();
+        self.resumed.store(position, Ordering::SeqCst);
+    }
+
+    pub fn wrap_read<R: Read>(&self, read: R) -> impl Read {
+        self.bar.wrap_read(read)
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+    }
+
+    pub fn finish(&self, success: bool) {
+        self.bar.finish_and_clear();
+        MULTI_PROGRESS.remove(&self.bar);
+        if !success {
+            return;
+        }
+        let size = self.bar.position();
+        let received = size - self.resumed.load(Ordering::SeqCst).min(size);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        info!(
+            "{}: {} in {:.1}s ({}/s)",
+            &self.name,
+            HumanBytes(size),
+            elapsed,
+            HumanBytes((received as f64 / elapsed.max(0.001)) as u64)
+        );
+    }
+}
+
+pub struct Counter {
+    bar: ProgressBar,
+    total: usize,
+    enabled: bool,
+}
+
+impl Counter {
+    pub fn new(name: &str, total: usize, enabled: bool) -> Counter {
+        let bar = match enabled {
+            true => MULTI_PROGRESS.add(ProgressBar::new(total as u64)),
+            false => ProgressBar::hidden(),
+        };
+        bar.set_style(ProgressStyle::with_template("{msg} [{pos}/{len}] {wide_bar}").unwrap());
+        bar.set_message(name.to_string());
+        Counter {
+            bar,
+            total,
+            enabled,
+        }
+    }
+
+    pub fn inc(&self, name: &str, success: bool) {
+        self.bar.inc(1);
+        if !self.enabled {
+            let status = match success {
+                true => "done",
+                false => "failed",
+            };
+            info!(
+                "[{}/{}] {} {}",
+                self.bar.position(),
+                self.total,
+                name,
+                status
+            );
+        }
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+        MULTI_PROGRESS.remove(&self.bar);
+    }
+}
@@ -0,0 +1,794 @@
+use log::debug;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::StatusCode;
+use serde_json::json;
+use serde_json::value as json_value;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::HelmError;
+use crate::extension::{self, Extension, SORT_OPTIONS};
+use crate::gallery::{
+    GalleryExtension, GalleryPublisher, GalleryResponse, GalleryResult, GalleryVersion,
+};
+use crate::progress::FileProgress;
+use crate::retry::{RetryError, RetryPolicy};
+use crate::server;
+use crate::utils;
+
+static QUERY_BATCH_SIZE: usize = 50;
+
+// the source of the extensions and the vscode server releases used by Helm,
+// the downloads write the final file, the caller handles the cache
+pub trait Registry: Debug + Send + Sync {
+    fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError>;
+
+    // the result is keyed by the lowercased extension id
+    fn query_extensions(
+        &self,
+        ext_ids: &[String],
+        flags: Option<usize>,
+    ) -> Result<HashMap<String, GalleryExtension>, HelmError> {
+        let mut result: HashMap<String, GalleryExtension> = HashMap::new();
+        for ext_id in ext_ids {
+            let (publisher, package) = match ext_id.split_once(".") {
+                Some(v) => v,
+                None => continue,
+            };
+            match self.query_extension(publisher, package, flags) {
+                Ok(v) => {
+                    result.insert(ext_id.to_lowercase(), v);
+                }
+                Err(HelmError::ExtensionNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(result)
+    }
+
+    fn search_extension(
+        &self,
+        _text: &str,
+        _sort_by: &str,
+        _page_number: usize,
+        _page_size: usize,
+    ) -> Result<(Vec<GalleryExtension>, usize), HelmError> {
+        Err(HelmError::Unsupported(String::from("searching extensions")))
+    }
+
+    fn download_extension(
+        &self,
+        extension: &Extension,
+        output_file: &str,
+        resume: bool,
+    ) -> Result<(), HelmError>;
+
+    // the commits are ordered from the latest
+    fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError>;
+
+    // returns the archive file, archive_path with the archive suffix
+    fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        archive_path: &str,
+    ) -> Result<String, HelmError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRegistry {
+    pub(crate) query_url: String,
+    pub(crate) download_url: Option<String>,
+    pub(crate) update_url: String,
+    pub(crate) client: Client,
+    pub(crate) download_client: Client,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) progress: bool,
+}
+
+// the vsix and server archive files in a directory laid out like the sync output:
+// extensions/<publisher>.<package>@<version>[=<platform>].vsix
+// server/<quality>/<platform>-<arch>/vscode-<prefix>-<arch>-<commit>.tar.gz
+#[derive(Debug, Clone)]
+pub struct LocalRegistry {
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRegistry {
+    extensions: Vec<(Extension, Vec<u8>)>,
+    releases: Vec<MemoryRelease>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryRelease {
+    quality: String,
+    platform: String,
+    arch: String,
+    commit: String,
+    archive_ext: String,
+    data: Vec<u8>,
+}
+
+impl HttpRegistry {
+    pub fn get_query_url(&self) -> &str {
+        &self.query_url
+    }
+
+    pub fn get_download_url(&self) -> Option<&str> {
+        self.download_url.as_deref()
+    }
+
+    pub fn get_update_url(&self) -> &str {
+        &self.update_url
+    }
+
+    pub fn get_client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn download_file(
+        &self,
+        name: &str,
+        url: &str,
+        body_file: &str,
+        resume: bool,
+    ) -> Result<HeaderMap, HelmError> {
+        let progress = FileProgress::new(name, self.progress);
+        let result = self.retry.run(name, || {
+            let offset = match resume {
+                true => fs::metadata(body_file).map_or(0, |x| x.len()),
+                false => 0,
+            };
+            let mut request = self.download_client.get(url);
+            if offset > 0 {
+                debug!("resume downloading {} from {}", body_file, offset);
+                request = request.header(RANGE, format!("bytes={}-", offset));
+            }
+            let response = request.send().map_err(utils::get_request_retry_error)?;
+            if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                fs::remove_file(body_file)?;
+                let error = HelmError::InvalidResponse {
+                    url: url.to_string(),
+                    message: format!("unable to resume downloading {}", body_file),
+                };
+                return Err(RetryError::transient(error, None));
+            }
+            let response = utils::check_response_status(response)?;
+            let headers = response.headers().clone();
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let position = if partial { offset } else { 0 };
+            progress.start(response.content_length().map(|x| x + position), position);
+            let mut f_o = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(partial)
+                .truncate(!partial)
+                .open(body_file)?;
+            io::copy(&mut progress.wrap_read(response), &mut f_o).map_err(|e| {
+                let error = HelmError::from(e).context(format!("download {} failed", url));
+                RetryError::transient(error, None)
+            })?;
+            // the body is renamed or decoded into place by the callers
+            f_o.sync_all()?;
+            Ok(headers)
+        });
+        progress.finish(result.is_ok());
+        result
+    }
+
+    fn query_gallery(
+        &self,
+        filters: &json_value::Value,
+        flags: usize,
+    ) -> Result<GalleryResult, HelmError> {
+        let payload = extension::get_gallery_payload(filters, flags);
+        let query_url = self.get_query_url();
+        let data: GalleryResponse = self.retry.run("query gallery", || {
+            let mut request = self.client.post(query_url);
+            for (key, val) in &extension::GALLERY_HEADERS {
+                request = request.header(*key, *val);
+            }
+            let response = request
+                .json(&payload)
+                .send()
+                .map_err(utils::get_request_retry_error)?;
+            let response = utils::check_response_status(response)?;
+            Ok(response.json()?)
+        })?;
+        extension::get_gallery_result(data, query_url)
+    }
+}
+
+impl Registry for HttpRegistry {
+    fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError> {
+        let ext_name = extension::get_extension_name(publisher, package, None, None);
+        let filters = extension::get_extension_filters(&ext_name);
+        let data = self
+            .query_gallery(&filters, flags.unwrap_or(extension::QUERY_FLAGS))
+            .map_err(|e| e.context(format!("query extension {} info failed", &ext_name)))?;
+        extension::get_first_extension(data, ext_name)
+    }
+
+    fn query_extensions(
+        &self,
+        ext_ids: &[String],
+        flags: Option<usize>,
+    ) -> Result<HashMap<String, GalleryExtension>, HelmError> {
+        let flags = flags.unwrap_or(extension::QUERY_FLAGS);
+        let mut ext_ids: Vec<String> = ext_ids.iter().map(|x| x.to_lowercase()).collect();
+        ext_ids.sort();
+        ext_ids.dedup();
+        let mut result: HashMap<String, GalleryExtension> = HashMap::new();
+        for chunk in ext_ids.chunks(QUERY_BATCH_SIZE) {
+            let mut criteria =
+                vec![json!({"filterType": 8, "value": "Microsoft.VisualStudio.Code"})];
+            criteria.extend(chunk.iter().map(|x| json!({"filterType": 7, "value": x})));
+            let mut page_number = 1;
+            loop {
+                let filters = json!([{
+                    "criteria": criteria,
+                    "pageNumber": page_number,
+                    "pageSize": chunk.len(),
+                }]);
+                let data = self.query_gallery(&filters, flags).map_err(|e| {
+                    e.context(format!("query extensions {} failed", chunk.join(" ")))
+                })?;
+                let count = data.extensions.len();
+                let total = data.get_result_count().unwrap_or(count);
+                debug!(
+                    "query extensions page {}: {} of {} found",
+                    page_number, count, total
+                );
+                for ext_data in data.extensions {
+                    result.insert(ext_data.get_extension_id().to_lowercase(), ext_data);
+                }
+                if count == 0 || page_number * chunk.len() >= total {
+                    break;
+                }
+                page_number += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    fn search_extension(
+        &self,
+        text: &str,
+        sort_by: &str,
+        page_number: usize,
+        page_size: usize,
+    ) -> Result<(Vec<GalleryExtension>, usize), HelmError> {
+        let sort_by = SORT_OPTIONS
+            .iter()
+            .find(|x| x.0 == sort_by)
+            .ok_or(HelmError::InvalidOption {
+                name: String::from("sort option"),
+                value: sort_by.to_string(),
+            })?
+            .1;
+        let sort_order = match sort_by {
+            0 => 0,
+            _ => 2,
+        };
+        let filters = json!([{
+            "criteria": [
+                {"filterType": 8, "value": "Microsoft.VisualStudio.Code"},
+                {"filterType": 10, "value": text},
+                {"filterType": 12, "value": "4096"},
+            ],
+            "pageNumber": page_number,
+            "pageSize": page_size,
+            "sortBy": sort_by,
+            "sortOrder": sort_order,
+        }]);
+        let data = self
+            .query_gallery(&filters, 0x301)
+            .map_err(|e| e.context(format!("search extension {} failed", text)))?;
+        let total = data.get_result_count().unwrap_or(data.extensions.len());
+        Ok((data.extensions, total))
+    }
+
+    fn download_extension(
+        &self,
+        extension: &Extension,
+        output_file: &str,
+        resume: bool,
+    ) -> Result<(), HelmError> {
+        let ext_name = extension.get_extension_name();
+        let download_url = extension::get_vsix_url(self.get_download_url(), extension)?;
+        debug!("downloading {}:\nURL: {}", &ext_name, &download_url);
+        let body_file = format!("{}.downloading", output_file);
+        let name = format!("download extension {}", &ext_name);
+        let headers = self.download_file(&name, &download_url, &body_file, resume)?;
+        extension::decode_vsix(&body_file, output_file, &headers)
+    }
+
+    fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError> {
+        let url = server::get_releases_url(self.get_update_url(), quality, platform, arch);
+        let data: json_value::Value = self.retry.run("query vscode server", || {
+            debug!("query vscode server releases: {}", &url);
+            let response = self
+                .client
+                .get(&url)
+                .send()
+                .map_err(utils::get_request_retry_error)?;
+            let response = utils::check_response_status(response)?;
+            Ok(response.json()?)
+        })?;
+        server::parse_commits(&data, url)
+    }
+
+    fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        archive_path: &str,
+    ) -> Result<String, HelmError> {
+        let url =
+            server::get_release_file_url(self.get_update_url(), quality, commit, prefix, arch);
+        let body_file = format!("{}.downloading", archive_path);
+        let name = format!("download vscode server {}", commit);
+        let headers = self.download_file(&name, &url, &body_file, true)?;
+        let archive_file = format!("{}{}", archive_path, server::get_archive_ext(&headers));
+        debug!("archive file {}", &archive_file);
+        fs::rename(body_file, &archive_file)?;
+        Ok(archive_file)
+    }
+}
+
+impl LocalRegistry {
+    pub fn new(root: &str) -> LocalRegistry {
+        LocalRegistry {
+            root: PathBuf::from(shellexpand::tilde(root).to_string()),
+        }
+    }
+
+    fn list_files(&self, dir: &Path) -> Result<Vec<PathBuf>, HelmError> {
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut files: Vec<PathBuf> = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn get_release_dir(&self, quality: &str, platform: &str, arch: &str) -> PathBuf {
+        self.root
+            .join("server")
+            .join(quality)
+            .join(format!("{}-{}", platform, arch))
+    }
+}
+
+impl Registry for LocalRegistry {
+    fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        _flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError> {
+        let ext_id = extension::get_extension_name(publisher, package, None, None);
+        let extensions: Vec<Extension> = self
+            .list_files(&self.root.join("extensions"))?
+            .iter()
+            .filter_map(|x| x.file_name().and_then(|x| x.to_str()))
+            .filter(|x| x.ends_with(".vsix"))
+            .filter_map(extension::parse_ext_line)
+            .filter(|x| x.get_version().is_some())
+            .filter(|x| x.get_extension_id().eq_ignore_ascii_case(&ext_id))
+            .collect();
+        get_gallery_extension(&ext_id, extensions.iter())
+    }
+
+    fn download_extension(
+        &self,
+        extension: &Extension,
+        output_file: &str,
+        _resume: bool,
+    ) -> Result<(), HelmError> {
+        let ext_name = extension.get_extension_name();
+        let vsix_file = self
+            .root
+            .join("extensions")
+            .join(format!("{}.vsix", &ext_name));
+        if !vsix_file.is_file() {
+            return Err(HelmError::VersionNotFound(ext_name));
+        }
+        debug!("copy {} to {}", vsix_file.display(), output_file);
+        copy_file_atomic(&vsix_file, Path::new(output_file))
+    }
+
+    fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError> {
+        let prefix = format!("vscode-{}-{}-", server::get_release_prefix(platform), arch);
+        let mut releases: Vec<(String, PathBuf)> = self
+            .list_files(&self.get_release_dir(quality, platform, arch))?
+            .into_iter()
+            .filter_map(|path| {
+                let file_name = path.file_name()?.to_str()?;
+                let archive_name = [".tar.gz", ".zip"]
+                    .iter()
+                    .find_map(|x| file_name.strip_suffix(x))?;
+                let commit = archive_name.strip_prefix(&prefix)?.to_string();
+                Some((commit, path))
+            })
+            .collect();
+        let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+        releases.sort_by_key(|x| std::cmp::Reverse(modified(&x.1)));
+        Ok(releases.into_iter().map(|x| x.0).collect())
+    }
+
+    fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        archive_path: &str,
+    ) -> Result<String, HelmError> {
+        let platform = prefix.split_once("-").map_or(prefix, |x| x.1);
+        let release_dir = self.get_release_dir(quality, platform, arch);
+        let archive_name = format!("vscode-{}-{}-{}", prefix, arch, commit);
+        for archive_ext in [".tar.gz", ".zip"] {
+            let source = release_dir.join(format!("{}{}", &archive_name, archive_ext));
+            if source.is_file() {
+                let archive_file = format!("{}{}", archive_path, archive_ext);
+                debug!("copy {} to {}", source.display(), &archive_file);
+                copy_file_atomic(&source, Path::new(&archive_file))?;
+                return Ok(archive_file);
+            }
+        }
+        Err(HelmError::ReleaseNotFound {
+            quality: quality.to_string(),
+            platform: platform.to_string(),
+            arch: arch.to_string(),
+        })
+    }
+}
+
+impl MemoryRegistry {
+    pub fn new() -> MemoryRegistry {
+        MemoryRegistry::default()
+    }
+
+    // the extension needs a version, e.g. parse_ext_line("pub.pkg@1.0.0=linux-x64")
+    pub fn add_extension(&mut self, extension: Extension, data: Vec<u8>) {
+        self.extensions.push((extension, data));
+    }
+
+    // the releases are listed from the latest added
+    pub fn add_release(
+        &mut self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+        commit: &str,
+        archive_ext: &str,
+        data: Vec<u8>,
+    ) {
+        self.releases.push(MemoryRelease {
+            quality: quality.to_string(),
+            platform: platform.to_string(),
+            arch: arch.to_string(),
+            commit: commit.to_string(),
+            archive_ext: archive_ext.to_string(),
+            data,
+        });
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn query_extension(
+        &self,
+        publisher: &str,
+        package: &str,
+        _flags: Option<usize>,
+    ) -> Result<GalleryExtension, HelmError> {
+        let ext_id = extension::get_extension_name(publisher, package, None, None);
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|x| &x.0)
+            .filter(|x| x.get_version().is_some())
+            .filter(|x| x.get_extension_id().eq_ignore_ascii_case(&ext_id));
+        get_gallery_extension(&ext_id, extensions)
+    }
+
+    fn download_extension(
+        &self,
+        extension: &Extension,
+        output_file: &str,
+        _resume: bool,
+    ) -> Result<(), HelmError> {
+        let ext_name = extension.get_extension_name();
+        match self
+            .extensions
+            .iter()
+            .find(|x| x.0.get_extension_name() == ext_name)
+        {
+            Some((_, data)) => utils::write_file_atomic(Path::new(output_file), data),
+            None => Err(HelmError::VersionNotFound(ext_name)),
+        }
+    }
+
+    fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError> {
+        Ok(self
+            .releases
+            .iter()
+            .rev()
+            .filter(|x| x.quality == quality && x.platform == platform && x.arch == arch)
+            .map(|x| x.commit.clone())
+            .collect())
+    }
+
+    fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        archive_path: &str,
+    ) -> Result<String, HelmError> {
+        let release = self.releases.iter().find(|x| {
+            x.quality == quality
+                && x.commit == commit
+                && x.arch == arch
+                && server::get_release_prefix(&x.platform) == prefix
+        });
+        match release {
+            Some(v) => {
+                let archive_file = format!("{}{}", archive_path, &v.archive_ext);
+                utils::write_file_atomic(Path::new(&archive_file), &v.data)?;
+                Ok(archive_file)
+            }
+            None => Err(HelmError::ReleaseNotFound {
+                quality: quality.to_string(),
+                platform: prefix.to_string(),
+                arch: arch.to_string(),
+            }),
+        }
+    }
+}
+
+fn get_gallery_extension<'a>(
+    ext_id: &str,
+    extensions: impl Iterator<Item = &'a Extension>,
+) -> Result<GalleryExtension, HelmError> {
+    let mut extensions: Vec<&Extension> = extensions.collect();
+    let first = match extensions.first() {
+        Some(v) => *v,
+        None => return Err(HelmError::ExtensionNotFound(ext_id.to_string())),
+    };
+    // the gallery lists the versions from the latest
+    extensions.sort_by(|a, b| {
+        extension::compare_version(
+            b.get_version().unwrap_or_default(),
+            a.get_version().unwrap_or_default(),
+        )
+    });
+    Ok(GalleryExtension {
+        extension_name: first.get_package().to_string(),
+        display_name: first.get_package().to_string(),
+        publisher: GalleryPublisher {
+            publisher_name: first.get_publisher().to_string(),
+            display_name: first.get_publisher().to_string(),
+            ..GalleryPublisher::default()
+        },
+        versions: extensions
+            .iter()
+            .map(|x| GalleryVersion {
+                version: x.get_version().unwrap_or_default().to_string(),
+                target_platform: x.get_platform().map(str::to_string),
+                ..GalleryVersion::default()
+            })
+            .collect(),
+        ..GalleryExtension::default()
+    })
+}
+
+fn copy_file_atomic(source: &Path, target: &Path) -> Result<(), HelmError> {
+    let temp_path = utils::get_temp_path(target);
+    let result = fs::copy(source, &temp_path).and_then(|_| fs::rename(&temp_path, target));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok(result.map(|_| ())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    static COMMIT_A: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    static COMMIT_B: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    fn get_versions(ext_data: &GalleryExtension) -> Vec<(&str, Option<&str>)> {
+        ext_data
+            .versions
+            .iter()
+            .map(|x| (x.version.as_str(), x.target_platform.as_deref()))
+            .collect()
+    }
+
+    fn make_local_registry() -> (TempDir, LocalRegistry) {
+        let dir = tempfile::tempdir().unwrap();
+        let ext_dir = dir.path().join("extensions");
+        fs::create_dir_all(&ext_dir).unwrap();
+        for name in [
+            "p.n@1.0.0.vsix",
+            "p.n@1.1.0=linux-x64.vsix",
+            "p.n@1.1.0.vsix.downloading",
+            "p.other@2.0.0.vsix",
+        ] {
+            fs::write(ext_dir.join(name), name).unwrap();
+        }
+        let release_dir = dir.path().join("server/stable/linux-x64");
+        fs::create_dir_all(&release_dir).unwrap();
+        let now = SystemTime::now();
+        for (commit, ext, age) in [(COMMIT_A, ".tar.gz", 60), (COMMIT_B, ".zip", 0)] {
+            let file = release_dir.join(format!("vscode-server-linux-x64-{}{}", commit, ext));
+            fs::write(&file, commit).unwrap();
+            let modified = now - Duration::from_secs(age);
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        fs::write(release_dir.join("vscode-server-linux-arm64-c.tar.gz"), "").unwrap();
+        let registry = LocalRegistry::new(dir.path().to_str().unwrap());
+        (dir, registry)
+    }
+
+    #[test]
+    fn test_local_registry_extensions() {
+        let (dir, registry) = make_local_registry();
+        let ext_data = registry.query_extension("P", "n", None).unwrap();
+        assert_eq!(
+            get_versions(&ext_data),
+            [("1.1.0", Some("linux-x64")), ("1.0.0", None)]
+        );
+        assert!(matches!(
+            registry.query_extension("p", "missing", None),
+            Err(HelmError::ExtensionNotFound(_))
+        ));
+
+        let output_file = dir.path().join("out.vsix");
+        let output_text = output_file.to_str().unwrap();
+        let ext = extension::parse_ext_line("p.n@1.1.0=linux-x64").unwrap();
+        registry
+            .download_extension(&ext, output_text, false)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&output_file).unwrap(),
+            "p.n@1.1.0=linux-x64.vsix"
+        );
+        let ext = extension::parse_ext_line("p.n@1.2.0").unwrap();
+        assert!(matches!(
+            registry.download_extension(&ext, output_text, false),
+            Err(HelmError::VersionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_local_registry_releases() {
+        let (dir, registry) = make_local_registry();
+        let commits = registry.list_releases("stable", "linux", "x64").unwrap();
+        assert_eq!(commits, [COMMIT_B, COMMIT_A]);
+        assert!(registry
+            .list_releases("insider", "linux", "x64")
+            .unwrap()
+            .is_empty());
+
+        let archive_path = dir.path().join("vscode-server-linux-x64-a");
+        let archive_path = archive_path.to_str().unwrap();
+        let archive_file = registry
+            .download_release_file("stable", COMMIT_A, "server-linux", "x64", archive_path)
+            .unwrap();
+        assert_eq!(archive_file, format!("{}.tar.gz", archive_path));
+        assert_eq!(fs::read_to_string(&archive_file).unwrap(), COMMIT_A);
+        assert!(matches!(
+            registry.download_release_file("stable", "c", "server-linux", "x64", archive_path),
+            Err(HelmError::ReleaseNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_memory_registry_releases() {
+        let mut registry = MemoryRegistry::new();
+        registry.add_release("stable", "linux", "x64", COMMIT_A, ".tar.gz", b"a".to_vec());
+        registry.add_release(
+            "stable",
+            "alpine",
+            "x64",
+            COMMIT_A,
+            ".tar.gz",
+            b"c".to_vec(),
+        );
+        registry.add_release("stable", "linux", "x64", COMMIT_B, ".zip", b"b".to_vec());
+        let commits = registry.list_releases("stable", "linux", "x64").unwrap();
+        assert_eq!(commits, [COMMIT_B, COMMIT_A]);
+        assert_eq!(
+            registry.list_releases("stable", "alpine", "x64").unwrap(),
+            [COMMIT_A]
+        );
+        assert!(registry
+            .list_releases("stable", "linux", "arm64")
+            .unwrap()
+            .is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("vscode-server");
+        let archive_path = archive_path.to_str().unwrap();
+        let archive_file = registry
+            .download_release_file("stable", COMMIT_B, "server-linux", "x64", archive_path)
+            .unwrap();
+        assert_eq!(archive_file, format!("{}.zip", archive_path));
+        assert_eq!(fs::read(&archive_file).unwrap(), b"b");
+        let archive_file = registry
+            .download_release_file("stable", COMMIT_A, "cli-alpine", "x64", archive_path)
+            .unwrap();
+        assert_eq!(fs::read(&archive_file).unwrap(), b"c");
+        assert!(matches!(
+            registry.download_release_file(
+                "insider",
+                COMMIT_A,
+                "server-linux",
+                "x64",
+                archive_path
+            ),
+            Err(HelmError::ReleaseNotFound { .. })
+        ));
+    }
+}
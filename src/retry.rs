@@ -0,0 +1,200 @@
+use log::{debug, warn};
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::config;
+use crate::error::HelmError;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+#[derive(Debug)]
+pub struct RetryError {
+    pub error: HelmError,
+    pub retryable: bool,
+    pub retry_after: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config() -> RetryPolicy {
+        let retry = &config::get_config().retry;
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            retries: retry.retries.unwrap_or(default.retries),
+            initial_delay: retry
+                .initial_delay
+                .map_or(default.initial_delay, Duration::from_secs_f64),
+            max_delay: retry
+                .max_delay
+                .map_or(default.max_delay, Duration::from_secs_f64),
+        }
+    }
+
+    pub fn get_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        // the server supplied delay is capped too, a far Retry-After must not stall the run
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        delay / 2 + delay.mul_f64(jitter as f64 / 2000.0)
+    }
+
+    pub fn run<T, F>(&self, name: &str, mut func: F) -> Result<T, HelmError>
+    where
+        F: FnMut() -> Result<T, RetryError>,
+    {
+        let attempts = self.retries + 1;
+        let mut attempt = 1;
+        loop {
+            debug!("{}: attempt {}/{}", name, attempt, attempts);
+            let error = match func() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            if !error.retryable || attempt >= attempts {
+                if attempt > 1 {
+                    debug!("{}: failed after {} attempts", name, attempt);
+                }
+                return Err(error.error);
+            }
+            let delay = self.get_delay(attempt, error.retry_after);
+            warn!(
+                "{}: attempt {}/{} failed, retry in {:.1}s: {}",
+                name,
+                attempt,
+                attempts,
+                delay.as_secs_f64(),
+                error.error
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn run_async<T, F, Fut>(&self, name: &str, mut func: F) -> Result<T, HelmError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RetryError>>,
+    {
+        let attempts = self.retries + 1;
+        let mut attempt = 1;
+        loop {
+            debug!("{}: attempt {}/{}", name, attempt, attempts);
+            let error = match func().await {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            if !error.retryable || attempt >= attempts {
+                if attempt > 1 {
+                    debug!("{}: failed after {} attempts", name, attempt);
+                }
+                return Err(error.error);
+            }
+            let delay = self.get_delay(attempt, error.retry_after);
+            warn!(
+                "{}: attempt {}/{} failed, retry in {:.1}s: {}",
+                name,
+                attempt,
+                attempts,
+                delay.as_secs_f64(),
+                error.error
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl RetryError {
+    pub fn transient(error: HelmError, retry_after: Option<Duration>) -> RetryError {
+        RetryError {
+            error,
+            retryable: true,
+            retry_after,
+        }
+    }
+
+    pub fn from_status(status: u16, retry_after: Option<&str>, url: String) -> RetryError {
+        RetryError {
+            error: HelmError::HttpStatus { url, status },
+            retryable: is_retryable_status(status),
+            retry_after: retry_after.and_then(parse_retry_after),
+        }
+    }
+}
+
+impl<E: Into<HelmError>> From<E> for RetryError {
+    fn from(error: E) -> Self {
+        RetryError {
+            error: error.into(),
+            retryable: false,
+            retry_after: None,
+        }
+    }
+}
+
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_delay_caps_retry_after() {
+        let retry = RetryPolicy::default();
+        let delay = retry.get_delay(1, Some(Duration::from_secs(86400)));
+        assert_eq!(delay, retry.max_delay);
+        let delay = retry.get_delay(1, Some(Duration::from_secs(5)));
+        assert_eq!(delay, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_get_delay_backoff() {
+        let retry = RetryPolicy::default();
+        for attempt in 1..10 {
+            let delay = retry.get_delay(attempt, None);
+            let base = retry
+                .initial_delay
+                .saturating_mul(2u32.pow(attempt - 1))
+                .min(retry.max_delay);
+            assert!(delay >= base / 2 && delay <= base);
+        }
+    }
+}
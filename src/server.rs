@@ -1,22 +1,264 @@
-use log::debug;
-use serde_json::from_str as json_from_str;
+use log::{debug, info};
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION};
 use serde_json::value as json_value;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::env;
-use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 
+use crate::cache;
+use crate::error::HelmError;
+use crate::helm::Helm;
+#[cfg(feature = "async")]
+use crate::nonblocking::{self, AsyncHelm};
 use crate::utils;
 
+impl Helm {
+    pub fn get_latest_release(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<String, HelmError> {
+        let commits = self.list_releases(quality, platform, arch)?;
+        match commits.first() {
+            None => Err(HelmError::ReleaseNotFound {
+                quality: quality.to_string(),
+                platform: platform.to_string(),
+                arch: arch.to_string(),
+            }),
+            Some(v) => Ok(v.clone()),
+        }
+    }
+
+    pub fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError> {
+        self.get_registry().list_releases(quality, platform, arch)
+    }
+
+    pub fn download_server(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+        commit: Option<&String>,
+        output_dir: &str,
+    ) -> Result<String, HelmError> {
+        let commit = match commit {
+            Some(v) => v.clone(),
+            None => self.get_latest_release(quality, platform, arch)?,
+        };
+        let prefix = get_release_prefix(platform);
+        let archive_file =
+            self.download_release_file(quality, &commit, &prefix, arch, output_dir)?;
+        prepare_release_dir(&commit, &archive_file, output_dir)?;
+        Ok(commit)
+    }
+
+    pub fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        output_dir: &str,
+    ) -> Result<String, HelmError> {
+        let archive_path = format!("vscode-{}-{}-{}", &prefix, &arch, &commit);
+        let archive_path = PathBuf::from(output_dir).join(archive_path);
+        let archive_path = archive_path.to_str().unwrap();
+        debug!("download vscode server release file to {}", archive_path);
+        fs::create_dir_all(output_dir)?;
+        let cache_key = cache::get_server_key(quality, prefix, arch, commit);
+        if let Some(cache) = self.get_cache() {
+            if let Some(entry) = cache.lookup(&cache_key) {
+                let archive_file = PathBuf::from(output_dir).join(&entry.file_name);
+                if cache.fetch(&cache_key, &archive_file)?.is_some() {
+                    return Ok(archive_file.to_string_lossy().to_string());
+                }
+            }
+        }
+        let archive_file = self.get_registry().download_release_file(
+            quality,
+            commit,
+            prefix,
+            arch,
+            archive_path,
+        )?;
+        if let Some(cache) = self.get_cache() {
+            cache.store(&cache_key, Path::new(&archive_file))?;
+        }
+        Ok(archive_file)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncHelm {
+    pub async fn get_latest_release(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<String, HelmError> {
+        let commits = self.list_releases(quality, platform, arch).await?;
+        match commits.first() {
+            None => Err(HelmError::ReleaseNotFound {
+                quality: quality.to_string(),
+                platform: platform.to_string(),
+                arch: arch.to_string(),
+            }),
+            Some(v) => Ok(v.clone()),
+        }
+    }
+
+    pub async fn list_releases(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+    ) -> Result<Vec<String>, HelmError> {
+        self.get_registry()
+            .list_releases(quality, platform, arch)
+            .await
+    }
+
+    pub async fn download_server(
+        &self,
+        quality: &str,
+        platform: &str,
+        arch: &str,
+        commit: Option<&String>,
+        output_dir: &str,
+    ) -> Result<String, HelmError> {
+        let commit = match commit {
+            Some(v) => v.clone(),
+            None => self.get_latest_release(quality, platform, arch).await?,
+        };
+        let prefix = get_release_prefix(platform);
+        let archive_file = self
+            .download_release_file(quality, &commit, &prefix, arch, output_dir)
+            .await?;
+        let (release, output_dir) = (commit.clone(), output_dir.to_string());
+        nonblocking::run_blocking(move || {
+            prepare_release_dir(&release, &archive_file, &output_dir)
+        })
+        .await?;
+        Ok(commit)
+    }
+
+    pub async fn download_release_file(
+        &self,
+        quality: &str,
+        commit: &str,
+        prefix: &str,
+        arch: &str,
+        output_dir: &str,
+    ) -> Result<String, HelmError> {
+        let archive_path = format!("vscode-{}-{}-{}", &prefix, &arch, &commit);
+        let archive_path = PathBuf::from(output_dir).join(archive_path);
+        let archive_path = archive_path.to_str().unwrap();
+        debug!("download vscode server release file to {}", archive_path);
+        tokio::fs::create_dir_all(output_dir).await?;
+        let cache_key = cache::get_server_key(quality, prefix, arch, commit);
+        if let Some(cache) = self.get_cache() {
+            if let Some(entry) = cache.lookup_async(&cache_key).await {
+                let archive_file = PathBuf::from(output_dir).join(&entry.file_name);
+                if cache
+                    .fetch_async(&cache_key, &archive_file)
+                    .await?
+                    .is_some()
+                {
+                    return Ok(archive_file.to_string_lossy().to_string());
+                }
+            }
+        }
+        let archive_file = self
+            .get_registry()
+            .download_release_file(quality, commit, prefix, arch, archive_path)
+            .await?;
+        if let Some(cache) = self.get_cache() {
+            cache
+                .store_async(&cache_key, Path::new(&archive_file))
+                .await?;
+        }
+        Ok(archive_file)
+    }
+}
+
+pub(crate) fn get_releases_url(
+    update_url: &str,
+    quality: &str,
+    platform: &str,
+    arch: &str,
+) -> String {
+    format!(
+        "{}/api/commits/{}/{}-{}",
+        update_url, quality, platform, arch
+    )
+}
+
+pub(crate) fn get_release_file_url(
+    update_url: &str,
+    quality: &str,
+    commit: &str,
+    prefix: &str,
+    arch: &str,
+) -> String {
+    format!(
+        "{}/commit:{}/{}-{}/{}",
+        update_url, commit, prefix, arch, quality
+    )
+}
+
+pub(crate) fn parse_commits(
+    data: &json_value::Value,
+    url: String,
+) -> Result<Vec<String>, HelmError> {
+    let commits = data.as_array().map(|x| {
+        x.iter()
+            .filter_map(|x| x.as_str().map(str::to_string))
+            .collect::<Vec<String>>()
+    });
+    match commits {
+        None => Err(HelmError::InvalidResponse {
+            url,
+            message: String::from("no commit id list found"),
+        }),
+        Some(v) => Ok(v),
+    }
+}
+
+pub(crate) fn get_archive_ext(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(utils::parse_content_disposition)
+        .and_then(|x| match x.rfind(".") {
+            None => None,
+            Some(pos) => {
+                let ext = if x[..pos].ends_with(".tar") {
+                    format!(".tar{}", &x[pos..])
+                } else {
+                    x[pos..].to_string()
+                };
+                Some(ext)
+            }
+        })
+        .map_or(String::from(".tar.gz"), |x| x)
+}
+
 pub fn get_platform_info(platform: &Option<String>, arch: &Option<String>) -> (String, String) {
-    let valid_map_p = vec![
+    let valid_map_p = [
         ("linux", "linux"),
         ("windows", "win32"),
         ("macos", "darwin"),
         ("alpine", "alpine"),
     ];
-    let valid_map_a = vec![("x86_64", "x64"), ("aarch64", "arm64"), ("arm", "armhf")];
+    let valid_map_a = [("x86_64", "x64"), ("aarch64", "arm64"), ("arm", "armhf")];
     let platform = match platform {
         Some(v) => v,
         None => {
@@ -46,116 +288,232 @@ pub fn get_platform_info(platform: &Option<String>, arch: &Option<String>) -> (S
     (platform.into(), arch.into())
 }
 
-pub fn get_latest_release(platform: &String, arch: &String) -> Result<String, Box<dyn Error>> {
-    let url = format!(
-        "https://update.code.visualstudio.com/api/commits/stable/{}-{}",
-        platform, arch
-    );
-    let curl_args = vec!["-fsSL", &url];
-    let prog_name = String::from("curl");
-    let prog_text = format!("{} {}", &prog_name, (&curl_args).join(" "));
-    debug!("exec command: {}", &prog_text);
-    let result = Command::new(&prog_name).args(&curl_args).output()?;
-    if !result.status.success() {
-        let error = String::from_utf8(result.stderr).map_or("".into(), |x| x);
-        return Err(format!("exec command {} failed: {}", prog_text, { error }).into());
-    }
-    let data = String::from_utf8(result.stdout)?;
-    let data: json_value::Value = json_from_str(&data)?;
-    let commit = data
-        .as_array()
-        .and_then(|x| x.first())
-        .and_then(|x| x.as_str());
-    match commit {
-        None => Err(format!("query vscode server commit id failed, url {}", &url).into()),
-        Some(v) => Ok(v.into()),
+pub fn get_latest_release(quality: &str, platform: &str, arch: &str) -> Result<String, HelmError> {
+    Helm::from_config()?.get_latest_release(quality, platform, arch)
+}
+
+pub fn list_releases(quality: &str, platform: &str, arch: &str) -> Result<Vec<String>, HelmError> {
+    Helm::from_config()?.list_releases(quality, platform, arch)
+}
+
+pub fn get_release_prefix(platform: &str) -> String {
+    match platform {
+        "alpine" => format!("cli-{}", platform),
+        _ => format!("server-{}", platform),
     }
 }
 
+pub fn download_server(
+    quality: &str,
+    platform: &str,
+    arch: &str,
+    commit: Option<&String>,
+    output_dir: &str,
+) -> Result<String, HelmError> {
+    Helm::from_config()?.download_server(quality, platform, arch, commit, output_dir)
+}
+
 pub fn download_release_file(
-    commit: &String,
-    prefix: &String,
-    arch: &String,
-    output_dir: &String,
-) -> Result<String, Box<dyn Error>> {
-    let archive_path = format!("vscode-{}-{}-{}", &prefix, &arch, &commit);
-    let archive_path = PathBuf::from(output_dir).join(archive_path);
-    let archive_path = archive_path.to_str().unwrap();
-    debug!("download vscode server release file to {}", archive_path);
-    fs::create_dir_all(output_dir)?;
-    let url = format!(
-        "https://update.code.visualstudio.com/commit:{}/{}-{}/stable",
-        commit, prefix, arch
-    );
-    let body_file = format!("{}.downloading", &archive_path);
-    let head_file = format!("{}.header", &archive_path);
-    let curl_args = vec![
-        "-fSL",
-        "-C",
-        "-",
-        &url,
-        "-o",
-        body_file.as_str(),
-        "-D",
-        head_file.as_str(),
-    ];
-    let prog_name = String::from("curl");
-    let prog_text = format!("{} {}", prog_name, curl_args.join(" "));
-    debug!("exec command:\n\t{}", prog_text);
-    let status = Command::new(&prog_name).args(&curl_args).status();
-    match status {
-        Ok(status) => {
-            if !status.success() {
-                return Err(format!("exec command {} failed", prog_text).into());
-            }
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
-    let archive_ext = utils::parse_http_header_content_disposition(&head_file)
-        .and_then(|x| match x.rfind(".") {
-            None => None,
-            Some(pos) => {
-                let ext = if x[..pos].ends_with(".tar") {
-                    format!(".tar{}", x[pos..].to_string())
-                } else {
-                    x[pos..].to_string()
-                };
-                Some(ext)
-            }
-        })
-        .map_or(String::from(".tar.gz"), |x| x);
-    let archive_file = format!("{}{}", archive_path, archive_ext);
-    debug!("archive file {}", &archive_file);
-    fs::rename(body_file, &archive_file)?;
-    fs::remove_file(head_file)?;
-    Ok(archive_file)
+    quality: &str,
+    commit: &str,
+    prefix: &str,
+    arch: &str,
+    output_dir: &str,
+) -> Result<String, HelmError> {
+    Helm::from_config()?.download_release_file(quality, commit, prefix, arch, output_dir)
 }
 
 pub fn prepare_release_dir(
-    commit: &String,
-    archive_file: &String,
-    output_dir: &String,
-) -> Result<(), Box<dyn Error>> {
+    commit: &str,
+    archive_file: &str,
+    output_dir: &str,
+) -> Result<(), HelmError> {
     debug!("{} {} {}", commit, archive_file, output_dir);
     let output_dir = PathBuf::from(output_dir);
     let bin_dir = output_dir.join("bin");
     let commit_dir = bin_dir.join(commit);
-    if commit_dir.exists() {
-        fs::remove_dir_all(&commit_dir)?;
-    }
-    if !commit_dir.exists() {
-        fs::create_dir_all(&commit_dir)?;
-    }
-    let commit_dir = commit_dir.to_str().unwrap();
-    debug!("extract files from {} to {}", archive_file, commit_dir);
-    if archive_file.ends_with(".tar.gz") {
-        utils::extract_tgz(&archive_file, &commit_dir, true)?;
+    // extract into a temp dir first, the commit dir only appears when complete
+    let temp_dir = utils::get_temp_path(&commit_dir);
+    fs::create_dir_all(&temp_dir)?;
+    let temp_text = temp_dir.to_str().unwrap();
+    debug!("extract files from {} to {}", archive_file, temp_text);
+    let result = if archive_file.ends_with(".tar.gz") {
+        utils::extract_tgz(archive_file, temp_text, true)
     } else if archive_file.ends_with(".zip") {
-        utils::extract_zip(&archive_file, &commit_dir, true)?;
+        utils::extract_zip(archive_file, temp_text, true)
+    } else {
+        Err(HelmError::Extract {
+            archive: archive_file.to_string(),
+            message: String::from("unknown archive format"),
+            source: None,
+        })
+    };
+    if let Err(e) = result {
+        fs::remove_dir_all(&temp_dir)?;
+        return Err(e);
+    }
+    if commit_dir.exists() {
+        let old_dir = utils::get_temp_path(&commit_dir);
+        fs::rename(&commit_dir, &old_dir)?;
+        fs::rename(&temp_dir, &commit_dir)?;
+        fs::remove_dir_all(&old_dir)?;
     } else {
-        return Err(format!("unable to extract file {}", &archive_file).into());
+        fs::rename(&temp_dir, &commit_dir)?;
     }
     Ok(())
 }
+
+// the other entries of the bin dir, e.g. the project files when the output dir is ".",
+// are not vscode server commits
+fn is_commit_id(name: &str) -> bool {
+    name.len() == 40 && name.chars().all(|x| x.is_ascii_hexdigit())
+}
+
+pub fn prune_release_dirs(
+    output_dir: &String,
+    keep: usize,
+    keep_commits: Option<&Vec<String>>,
+    releases: &[String],
+    dry_run: bool,
+) -> Result<(Vec<String>, u64), HelmError> {
+    let output_dir = PathBuf::from(output_dir);
+    let bin_dir = output_dir.join("bin");
+    let mut commits: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    // the temp dirs of the extractions, removed when no running process owns them
+    let mut stale_paths: Vec<PathBuf> = vec![];
+    if bin_dir.is_dir() {
+        for entry in fs::read_dir(&bin_dir)? {
+            let path = entry?.path();
+            let commit = path.file_name().and_then(|x| x.to_str());
+            if let Some(commit) = commit.filter(|x| is_commit_id(x)) {
+                commits
+                    .entry(commit.to_string())
+                    .or_default()
+                    .push(path.clone());
+            } else if commit
+                .and_then(|x| x.split_once('.'))
+                .is_some_and(|x| is_commit_id(x.0) && x.1.ends_with(".tmp"))
+                && utils::is_stale_temp_path(&path)
+            {
+                stale_paths.push(path);
+            }
+        }
+    }
+    for entry in fs::read_dir(&output_dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if !path.is_file() || !file_name.starts_with("vscode-") {
+            continue;
+        }
+        let archive_name = [".tar.gz", ".zip", ".downloading", ".header"]
+            .iter()
+            .find_map(|x| file_name.strip_suffix(x));
+        let commit = archive_name.and_then(|x| x.rsplit_once("-")).map(|x| x.1);
+        if let Some(commit) = commit.filter(|x| is_commit_id(x)) {
+            commits
+                .entry(commit.to_string())
+                .or_default()
+                .push(path.clone());
+        }
+    }
+    let modified = |paths: &Vec<PathBuf>| {
+        paths
+            .iter()
+            .filter_map(|x| fs::metadata(x).and_then(|x| x.modified()).ok())
+            .max()
+    };
+    let mut commits: Vec<(String, Vec<PathBuf>)> = commits.into_iter().collect();
+    commits.sort_by(|a, b| {
+        let pos_a = releases.iter().position(|x| *x == a.0);
+        let pos_b = releases.iter().position(|x| *x == b.0);
+        match (pos_a, pos_b) {
+            (Some(v1), Some(v2)) => v1.cmp(&v2),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => modified(&b.1).cmp(&modified(&a.1)),
+        }
+    });
+    let mut removed: Vec<String> = vec![];
+    let mut reclaimed: u64 = 0;
+    let mut removed_paths = stale_paths;
+    for (index, (commit, paths)) in commits.into_iter().enumerate() {
+        let retained = match keep_commits {
+            Some(keep_commits) => keep_commits.contains(&commit),
+            None => index < keep,
+        };
+        if retained {
+            debug!("keep vscode server commit {}", commit);
+            continue;
+        }
+        removed_paths.extend(paths);
+    }
+    for path in &removed_paths {
+        reclaimed += utils::get_path_size(path)?;
+        let path_text = path.to_string_lossy().to_string();
+        if dry_run {
+            info!("would remove {}", &path_text);
+        } else if path.is_dir() {
+            info!("removing {}", &path_text);
+            fs::remove_dir_all(path)?;
+        } else {
+            info!("removing {}", &path_text);
+            fs::remove_file(path)?;
+        }
+        removed.push(path_text);
+    }
+    removed.sort();
+    Ok((removed, reclaimed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_release_dirs_skips_other_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (commit_a, commit_b) = ("a".repeat(40), "b".repeat(40));
+        // the temp dir of a process that is gone is removed, the one of this process is kept
+        let stale_name = format!("{}.{}-0.tmp", &commit_b, u32::MAX);
+        let temp_name = format!("{}.{}-0.tmp", &commit_b, std::process::id());
+        for name in [
+            &commit_a,
+            &commit_b,
+            &stale_name,
+            &temp_name,
+            "scripts",
+            "build-tools",
+        ] {
+            fs::create_dir_all(dir.path().join("bin").join(name)).unwrap();
+        }
+        for name in [
+            format!("vscode-server-linux-x64-{}.tar.gz", &commit_a),
+            String::from("vscode-server-linux-x64-latest.tar.gz"),
+        ] {
+            fs::write(dir.path().join(name), b"archive").unwrap();
+        }
+        let output_dir = dir.path().to_string_lossy().to_string();
+        let releases = vec![commit_b.clone()];
+        prune_release_dirs(&output_dir, 1, None, &releases, false).unwrap();
+        let mut names: Vec<String> = fs::read_dir(dir.path().join("bin"))
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [commit_b, temp_name, "build-tools".into(), "scripts".into()]
+        );
+        assert!(dir
+            .path()
+            .join("vscode-server-linux-x64-latest.tar.gz")
+            .is_file());
+        assert!(!dir
+            .path()
+            .join(format!("vscode-server-linux-x64-{}.tar.gz", &commit_a))
+            .exists());
+    }
+}
@@ -0,0 +1,238 @@
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::error::HelmError;
+use crate::extension::{self, Extension};
+use crate::server;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    pub output_dir: Option<String>,
+    pub servers: Vec<ServerEntry>,
+    pub extension_sets: BTreeMap<String, Vec<ExtensionEntry>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerEntry {
+    #[serde(default = "default_quality")]
+    pub quality: String,
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    #[serde(default)]
+    pub arches: Vec<String>,
+    pub commit: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ExtensionEntry {
+    Line(String),
+    Table {
+        id: String,
+        version: Option<String>,
+        platform: Option<String>,
+    },
+}
+
+fn default_quality() -> String {
+    String::from("stable")
+}
+
+impl ExtensionEntry {
+    pub fn to_extension(&self) -> Result<Extension, HelmError> {
+        match self {
+            ExtensionEntry::Line(line) => extension::try_parse_ext_line(line),
+            ExtensionEntry::Table {
+                id,
+                version,
+                platform,
+            } => {
+                let (publisher, package) =
+                    id.split_once(".")
+                        .ok_or_else(|| HelmError::InvalidExtension {
+                            entry: id.to_string(),
+                            message: "expect <publisher>.<package>".to_string(),
+                        })?;
+                let ext =
+                    Extension::new(publisher, package, version.as_deref(), platform.as_deref());
+                ext.validate()?;
+                Ok(ext)
+            }
+        }
+    }
+}
+
+pub fn load_manifest(manifest_file: &str) -> Result<Manifest, HelmError> {
+    let data = config::read_config_file(Path::new(manifest_file))?;
+    let manifest: Manifest = serde_json::from_value(data)
+        .map_err(|e| HelmError::from(e).context(format!("invalid manifest {}", manifest_file)))?;
+    Ok(manifest)
+}
+
+pub fn sync_manifest(
+    manifest: &Manifest,
+    output_dir: &String,
+    cached: Option<bool>,
+    concurrency: usize,
+    prune: bool,
+) -> Result<Vec<String>, HelmError> {
+    let mut failed = sync_extensions(manifest, output_dir, cached, concurrency, prune)?;
+    failed.extend(sync_servers(manifest, output_dir, cached, prune)?);
+    Ok(failed)
+}
+
+fn sync_extensions(
+    manifest: &Manifest,
+    output_dir: &String,
+    cached: Option<bool>,
+    concurrency: usize,
+    prune: bool,
+) -> Result<Vec<String>, HelmError> {
+    let download_dir = PathBuf::from(output_dir).join("extensions");
+    let download_dir = download_dir.to_string_lossy().to_string();
+    let mut failed: Vec<String> = vec![];
+    let mut extensions: Vec<Extension> = vec![];
+    for (name, entries) in &manifest.extension_sets {
+        for entry in entries {
+            match entry.to_extension() {
+                Ok(v) => extensions.push(v),
+                Err(e) => {
+                    warn!("invalid extension entry in set {}: {}", name, e);
+                    failed.push(format!("{:?}", entry));
+                }
+            }
+        }
+    }
+    extensions.sort_by_key(|x| x.get_extension_name());
+    extensions.dedup_by_key(|x| x.get_extension_name());
+    let (resolved, resolve_failed) = extension::resolve_extensions(&extensions);
+    failed.extend(resolve_failed);
+    failed.extend(extension::download_extensions(
+        &resolved,
+        &download_dir,
+        cached,
+        concurrency,
+    ));
+    if !prune || !Path::new(&download_dir).is_dir() {
+        return Ok(failed);
+    }
+    if !failed.is_empty() {
+        warn!("some extensions are not resolved, skip removing unlisted extensions");
+        return Ok(failed);
+    }
+    let listed: Vec<String> = resolved
+        .iter()
+        .map(|x| format!("{}.vsix", x.get_extension_name()))
+        .collect();
+    for entry in fs::read_dir(&download_dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if path.is_file() && file_name.ends_with(".vsix") && !listed.iter().any(|x| x == file_name)
+        {
+            info!("removing unlisted extension {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(failed)
+}
+
+fn sync_servers(
+    manifest: &Manifest,
+    output_dir: &String,
+    cached: Option<bool>,
+    prune: bool,
+) -> Result<Vec<String>, HelmError> {
+    let server_dir = PathBuf::from(output_dir).join("server");
+    let mut failed: Vec<String> = vec![];
+    // the entries may share a target dir, e.g. a pinned commit and the latest one,
+    // so the commits are collected and each dir is pruned after all the entries
+    let mut listed: BTreeMap<PathBuf, Option<Vec<String>>> = BTreeMap::new();
+    for entry in &manifest.servers {
+        let (default_platform, default_arch) = server::get_platform_info(&None, &None);
+        let platforms = match entry.platforms.is_empty() {
+            true => vec![default_platform],
+            false => entry.platforms.clone(),
+        };
+        let arches = match entry.arches.is_empty() {
+            true => vec![default_arch],
+            false => entry.arches.clone(),
+        };
+        for platform in &platforms {
+            for arch in &arches {
+                let name = format!("{}/{}-{}", &entry.quality, platform, arch);
+                let target_dir = server_dir
+                    .join(&entry.quality)
+                    .join(format!("{}-{}", platform, arch));
+                let commits = listed.entry(target_dir.clone()).or_insert(Some(vec![]));
+                match sync_server(entry, platform, arch, &target_dir, cached) {
+                    Ok(commit) => {
+                        if let Some(v) = commits {
+                            v.push(commit);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("sync vscode server {} failed: {}", &name, e);
+                        failed.push(name);
+                        // keep the old commits of a dir that failed to update
+                        *commits = None;
+                    }
+                }
+            }
+        }
+    }
+    if !prune || !server_dir.is_dir() {
+        return Ok(failed);
+    }
+    for (target_dir, commits) in &listed {
+        if let Some(commits) = commits {
+            let target_text = target_dir.to_string_lossy().to_string();
+            server::prune_release_dirs(&target_text, 0, Some(commits), &[], false)?;
+        }
+    }
+    for quality_entry in fs::read_dir(&server_dir)? {
+        let quality_dir = quality_entry?.path();
+        if !quality_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&quality_dir)? {
+            let path = entry?.path();
+            if path.is_dir() && !listed.contains_key(&path) {
+                info!("removing unlisted vscode server {}", path.display());
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+fn sync_server(
+    entry: &ServerEntry,
+    platform: &str,
+    arch: &str,
+    target_dir: &Path,
+    cached: Option<bool>,
+) -> Result<String, HelmError> {
+    let commit = match &entry.commit {
+        Some(v) => v.clone(),
+        None => server::get_latest_release(&entry.quality, platform, arch)?,
+    };
+    let target_text = target_dir.to_string_lossy().to_string();
+    if cached.unwrap_or(true) && target_dir.join("bin").join(&commit).is_dir() {
+        info!(
+            "vscode server {} already exists in {}, skip downloading",
+            &commit, &target_text
+        );
+    } else {
+        server::download_server(&entry.quality, platform, arch, Some(&commit), &target_text)?;
+    }
+    Ok(commit)
+}
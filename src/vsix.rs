@@ -0,0 +1,317 @@
+use serde_json::value as json_value;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::error::{BoxError, HelmError};
+use crate::extension::{self, Extension};
+
+static MANIFEST_FILE: &str = "extension.vsixmanifest";
+static PACKAGE_FILE: &str = "extension/package.json";
+
+#[derive(Debug, Clone)]
+pub struct VsixPackage {
+    pub publisher: String,
+    pub name: String,
+    pub version: String,
+    pub target_platform: Option<String>,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub engine: Option<String>,
+}
+
+impl VsixPackage {
+    pub fn get_extension_id(&self) -> String {
+        format!("{}.{}", self.publisher, self.name)
+    }
+
+    pub fn to_extension(&self) -> Extension {
+        Extension::new(
+            &self.publisher,
+            &self.name,
+            Some(&self.version),
+            self.target_platform.as_deref(),
+        )
+    }
+}
+
+// the Identity element of the manifest, the package.json fields are used when it is missing
+struct Identity {
+    id: String,
+    version: String,
+    publisher: String,
+    target_platform: Option<String>,
+}
+
+fn get_metadata<'a>(document: &'a roxmltree::Document) -> Option<roxmltree::Node<'a, 'a>> {
+    document
+        .root_element()
+        .children()
+        .find(|x| x.has_tag_name("Metadata"))
+}
+
+fn parse_identity(document: &roxmltree::Document) -> Option<Identity> {
+    let node = get_metadata(document)?
+        .children()
+        .find(|x| x.has_tag_name("Identity"))?;
+    let target_platform = match node.attribute("TargetPlatform") {
+        Some("") | Some("undefined") | Some("universal") | None => None,
+        Some(v) => Some(v.to_string()),
+    };
+    Some(Identity {
+        id: node.attribute("Id")?.to_string(),
+        version: node.attribute("Version")?.to_string(),
+        publisher: node.attribute("Publisher")?.to_string(),
+        target_platform,
+    })
+}
+
+// the text of a Metadata child, or the value of a Metadata/Properties/Property
+fn get_manifest_text(document: &roxmltree::Document, name: &str) -> Option<String> {
+    let metadata = get_metadata(document)?;
+    let text = match metadata.children().find(|x| x.has_tag_name(name)) {
+        Some(node) => node.text(),
+        None => metadata
+            .children()
+            .find(|x| x.has_tag_name("Properties"))?
+            .children()
+            .find(|x| x.has_tag_name("Property") && x.attribute("Id") == Some(name))?
+            .attribute("Value"),
+    };
+    text.map(str::to_string).filter(|x| !x.is_empty())
+}
+
+pub fn read_vsix(vsix_file: &Path) -> Result<VsixPackage, HelmError> {
+    let mut archive = ZipArchive::new(File::open(vsix_file)?)
+        .map_err(|e| vsix_error(vsix_file, String::from("open vsix failed"), e))?;
+    let manifest = read_zip_text(vsix_file, &mut archive, MANIFEST_FILE)?;
+    let document = roxmltree::Document::parse(&manifest)
+        .map_err(|e| vsix_error(vsix_file, format!("invalid {}", MANIFEST_FILE), e))?;
+    if document.root_element().tag_name().name() != "PackageManifest" {
+        let message = format!("invalid {}: no PackageManifest element", MANIFEST_FILE);
+        return Err(invalid_vsix(vsix_file, message));
+    }
+    let identity = parse_identity(&document);
+    // the package.json is only required without the manifest Identity
+    let package = match archive.file_names().any(|x| x == PACKAGE_FILE) || identity.is_none() {
+        true => {
+            let package = read_zip_text(vsix_file, &mut archive, PACKAGE_FILE)?;
+            serde_json::from_str(&package)
+                .map_err(|e| vsix_error(vsix_file, format!("invalid {}", PACKAGE_FILE), e))?
+        }
+        false => json_value::Value::Null,
+    };
+    let get_opt = |key: &str| {
+        package
+            .get(key)
+            .and_then(|x| x.as_str())
+            .map(str::to_string)
+    };
+    let get_str = |key: &str| match get_opt(key) {
+        Some(v) => Ok(v),
+        None => Err(invalid_vsix(
+            vsix_file,
+            format!("invalid {}: no {} field", PACKAGE_FILE, key),
+        )),
+    };
+    let engine = package
+        .get("engines")
+        .and_then(|x| x.get("vscode"))
+        .and_then(|x| x.as_str())
+        .map(str::to_string);
+    let (publisher, name, version, target_platform) = match identity {
+        Some(identity) => (
+            identity.publisher,
+            identity.id,
+            identity.version,
+            identity.target_platform,
+        ),
+        None => (
+            get_str("publisher")?,
+            get_str("name")?,
+            get_str("version")?,
+            None,
+        ),
+    };
+    Ok(VsixPackage {
+        publisher,
+        name,
+        version,
+        target_platform,
+        display_name: get_opt("displayName")
+            .or_else(|| get_manifest_text(&document, "DisplayName")),
+        description: get_opt("description").or_else(|| get_manifest_text(&document, "Description")),
+        engine: engine
+            .or_else(|| get_manifest_text(&document, "Microsoft.VisualStudio.Code.Engine")),
+    })
+}
+
+pub fn verify_vsix(vsix_file: &Path) -> Result<VsixPackage, HelmError> {
+    let package = read_vsix(vsix_file)?;
+    let file_name = vsix_file
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    let ext = match extension::parse_ext_line(file_name) {
+        Some(v) => v,
+        None => {
+            let message = format!("unrecognized file name {}", file_name);
+            return Err(invalid_vsix(vsix_file, message));
+        }
+    };
+    if !ext
+        .get_extension_id()
+        .eq_ignore_ascii_case(&package.get_extension_id())
+    {
+        let message = format!(
+            "extension id {} does not match the file name",
+            package.get_extension_id()
+        );
+        return Err(invalid_vsix(vsix_file, message));
+    }
+    if let Some(version) = ext.get_version() {
+        if version != package.version {
+            let message = format!(
+                "extension version {} does not match the file name",
+                &package.version
+            );
+            return Err(invalid_vsix(vsix_file, message));
+        }
+    }
+    // a universal vsix may be saved under a platform suffix when the version is pinned
+    if let (Some(platform), Some(target_platform)) = (ext.get_platform(), &package.target_platform)
+    {
+        if platform != target_platform {
+            let message = format!(
+                "target platform {} does not match the file name",
+                target_platform
+            );
+            return Err(invalid_vsix(vsix_file, message));
+        }
+    }
+    Ok(package)
+}
+
+fn invalid_vsix(vsix_file: &Path, message: String) -> HelmError {
+    HelmError::InvalidVsix {
+        path: vsix_file.to_path_buf(),
+        message,
+        source: None,
+    }
+}
+
+fn vsix_error(vsix_file: &Path, message: String, error: impl Into<BoxError>) -> HelmError {
+    HelmError::InvalidVsix {
+        path: vsix_file.to_path_buf(),
+        message,
+        source: Some(error.into()),
+    }
+}
+
+fn read_zip_text(
+    vsix_file: &Path,
+    archive: &mut ZipArchive<File>,
+    name: &str,
+) -> Result<String, HelmError> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .map_err(BoxError::from)
+        .and_then(|mut x| x.read_to_string(&mut content).map_err(BoxError::from))
+        .map_err(|e| vsix_error(vsix_file, format!("read {} failed", name), e))?;
+    Ok(content.trim_start_matches('\u{feff}').to_string())
+}
+
+// write a vsix with the manifest and an optional package.json
+#[cfg(test)]
+pub(crate) fn write_test_vsix(vsix_file: &Path, manifest: &str, package: Option<&str>) {
+    use std::io::Write;
+    let mut writer = zip::ZipWriter::new(File::create(vsix_file).unwrap());
+    let mut files = vec![(MANIFEST_FILE, manifest)];
+    files.extend(package.map(|x| (PACKAGE_FILE, x)));
+    for (name, content) in files {
+        writer.start_file(name, Default::default()).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+#[cfg(test)]
+pub(crate) static TEST_MANIFEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<PackageManifest Version="2.0.0" xmlns="http://schemas.microsoft.com/developer/vsx-schema/2011">
+  <Metadata>
+    <Identity Language="en-US" Id="pkg" Version="1.2.0" Publisher="pub" TargetPlatform="linux-x64"/>
+    <DisplayName>Package</DisplayName>
+    <Properties>
+      <Property Id="Microsoft.VisualStudio.Code.Engine" Value="^1.90.0" />
+    </Properties>
+  </Metadata>
+</PackageManifest>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_read_vsix_keeps_zip_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let vsix_file = dir.path().join("p.n@1.0.0.vsix");
+        fs::write(&vsix_file, b"not a zip").unwrap();
+        match read_vsix(&vsix_file) {
+            Err(HelmError::InvalidVsix {
+                message,
+                source: Some(source),
+                ..
+            }) => {
+                assert_eq!(message, "open vsix failed");
+                assert!(source.downcast_ref::<zip::result::ZipError>().is_some());
+            }
+            v => panic!("expect InvalidVsix with a source, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_read_vsix_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        // the identity comes from the manifest, not the file name
+        let vsix_file = dir.path().join("renamed.vsix");
+        write_test_vsix(&vsix_file, TEST_MANIFEST, None);
+        let package = read_vsix(&vsix_file).unwrap();
+        assert_eq!(package.get_extension_id(), "pub.pkg");
+        assert_eq!(package.version, "1.2.0");
+        assert_eq!(package.target_platform.as_deref(), Some("linux-x64"));
+        assert_eq!(package.display_name.as_deref(), Some("Package"));
+        assert_eq!(package.engine.as_deref(), Some("^1.90.0"));
+        assert!(matches!(
+            verify_vsix(&vsix_file),
+            Err(HelmError::InvalidVsix { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_vsix_package_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let vsix_file = dir.path().join("pub.pkg@1.0.0.vsix");
+        let manifest = r#"<PackageManifest><Metadata><DisplayName>Old</DisplayName></Metadata>
+</PackageManifest>"#;
+        let package = r#"{"publisher": "pub", "name": "pkg", "version": "1.0.0",
+"displayName": "Package", "engines": {"vscode": "^1.80.0"}}"#;
+        write_test_vsix(&vsix_file, manifest, Some(package));
+        let package = verify_vsix(&vsix_file).unwrap();
+        assert_eq!(package.get_extension_id(), "pub.pkg");
+        assert_eq!(package.version, "1.0.0");
+        assert_eq!(package.target_platform, None);
+        assert_eq!(package.display_name.as_deref(), Some("Package"));
+        assert_eq!(package.engine.as_deref(), Some("^1.80.0"));
+        // without the identity the package.json is required
+        write_test_vsix(&vsix_file, manifest, None);
+        match read_vsix(&vsix_file) {
+            Err(HelmError::InvalidVsix { message, .. }) => {
+                assert_eq!(message, "read extension/package.json failed")
+            }
+            v => panic!("expect InvalidVsix, got {:?}", v),
+        }
+    }
+}